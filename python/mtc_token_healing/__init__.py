@@ -1,5 +1,16 @@
 from .mtc_token_healing import (
+    FimHealingDecision,
+    FimSuffixDecision,
+    HealingDecision,
+    HealingPolicy,
+    HealingPreference,
+    MutableTokenSeqTrie,
+    MutableTokenSeqTrieNodeId,
+    PrefixCache,
+    PrefixCacheNodeId,
+    PrefixMatch,
     SortedTokenRange,
+    TokenSeqSegment,
     TokenSeqTrieNode,
     VocabPrefixAutomaton,
     dfs_token_seq_trie,
@@ -9,9 +20,20 @@ TokenId = int
 SortedTokenId = int
 
 __all__ = [
+    "FimHealingDecision",
+    "FimSuffixDecision",
+    "HealingDecision",
+    "HealingPolicy",
+    "HealingPreference",
+    "MutableTokenSeqTrie",
+    "MutableTokenSeqTrieNodeId",
+    "PrefixCache",
+    "PrefixCacheNodeId",
+    "PrefixMatch",
     "SortedTokenId",
     "SortedTokenRange",
     "TokenId",
+    "TokenSeqSegment",
     "TokenSeqTrieNode",
     "VocabPrefixAutomaton",
     "dfs_token_seq_trie",
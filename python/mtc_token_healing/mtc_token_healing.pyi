@@ -1,5 +1,6 @@
 from collections.abc import Sequence
-from typing import Generic, TypeVar, overload
+from enum import Enum
+from typing import Generic, Literal, TypeVar, overload
 
 TokenId = int
 SortedTokenId = int
@@ -10,19 +11,54 @@ class SortedTokenRange:
 
     def __init__(self, lower: SortedTokenId = 0, upper: SortedTokenId = 0) -> None: ...
 
+class Utf8ClassColumns:
+    starts_mid_char: Sequence[bool]
+    ends_mid_char: Sequence[bool]
+    num_pending_bytes: Sequence[int]
+    is_ascii: Sequence[bool]
+
+    def __len__(self) -> int: ...
+
+class Utf8Constraint:
+    def allowed(
+        self, pending: bytes, within: SortedTokenRange | None = None
+    ) -> Sequence[SortedTokenRange]: ...
+    def heap_size(self) -> int: ...
+
 class VocabPrefixAutomaton:
-    def __init__(self, vocab: Sequence[bytes]) -> None: ...
+    def __init__(
+        self,
+        vocab: Sequence[bytes],
+        dense_threshold: int | None = None,
+        build_lcp: bool = False,
+        build_suffix_index: bool = False,
+    ) -> None: ...
     @property
     def vocab_size(self) -> int: ...
+    def heap_size(self) -> int: ...
+    def common_prefix(self, rank_range: SortedTokenRange) -> bytes | None: ...
+    def get_utf8_classes(self, sorted: bool = False) -> Utf8ClassColumns: ...
+    def utf8_constraint(self) -> Utf8Constraint: ...
     def get_order(self) -> Sequence[TokenId]: ...
     def get_rank(self) -> Sequence[SortedTokenId]: ...
-    def parse_bytes(self, inputs: bytes) -> Sequence[tuple[int, SortedTokenRange]]: ...
-    def parse_tokens(
+    def token_ids_of(self, token: bytes) -> Sequence[TokenId]: ...
+    def token_ids_ending_with(self, suffix: bytes) -> Sequence[TokenId]: ...
+    def decode(self, token_ids: Sequence[TokenId]) -> bytes: ...
+    def decode_with_offsets(
         self, token_ids: Sequence[TokenId]
+    ) -> tuple[bytes, Sequence[int]]: ...
+    def parse_bytes(
+        self, inputs: bytes, start_from: int, char_boundary_only: bool = False
+    ) -> Sequence[tuple[int, SortedTokenRange]]: ...
+    def parse_tokens(
+        self, token_ids: Sequence[TokenId], char_boundary_only: bool = False
     ) -> Sequence[tuple[bytes, SortedTokenRange]]: ...
     def parse_tokens_str_suffix(
-        self, token_ids: Sequence[TokenId]
+        self, token_ids: Sequence[TokenId], char_boundary_only: bool = False
     ) -> Sequence[tuple[str, SortedTokenRange]]: ...
+    def parse_tokens_str_suffix_lossless(
+        self, token_ids: Sequence[TokenId], char_boundary_only: bool = False
+    ) -> Sequence[tuple[str, bytes, SortedTokenRange]]: ...
     @overload
     def get_original_token_ids(self, sorted_token_id: SortedTokenId) -> TokenId: ...
     @overload
@@ -36,6 +72,64 @@ class VocabPrefixAutomaton:
         self, token_ids: Sequence[TokenId]
     ) -> Sequence[SortedTokenId]: ...
 
+class HealingPreference(Enum):
+    Deepest = 0
+    Shallowest = 1
+
+class HealingDecision:
+    num_removed_tokens: int
+    split_offset: int
+    removed_bytes: bytes
+    rank_range: SortedTokenRange
+
+class FimSuffixDecision:
+    num_removed_tokens: int
+    split_offset: int
+    removed_bytes: bytes
+    suffix_rank_range: SortedTokenRange
+
+class FimHealingDecision:
+    prefix: HealingDecision | None
+    suffix: FimSuffixDecision | None
+
+class HealingPolicy:
+    max_tokens: int
+    max_bytes: int
+    heal_through_whitespace: bool
+    heal_through_newline: bool
+    max_candidates: int
+    prefer: HealingPreference
+    barrier_token_ids: set[TokenId]
+
+    def __init__(
+        self,
+        max_tokens: int = ...,
+        max_bytes: int = ...,
+        heal_through_whitespace: bool = True,
+        heal_through_newline: bool = True,
+        max_candidates: int = ...,
+        prefer: HealingPreference = HealingPreference.Deepest,
+        barrier_token_ids: set[TokenId] = ...,
+    ) -> None: ...
+    @staticmethod
+    def permissive() -> HealingPolicy: ...
+    @staticmethod
+    def conservative() -> HealingPolicy: ...
+    @staticmethod
+    def line_local() -> HealingPolicy: ...
+    def decide(
+        self,
+        automaton: VocabPrefixAutomaton,
+        tokens: Sequence[TokenId],
+        min_index: int = 0,
+    ) -> HealingDecision | None: ...
+    def decide_fim(
+        self,
+        automaton: VocabPrefixAutomaton,
+        prefix_tokens: Sequence[TokenId],
+        suffix_tokens: Sequence[TokenId],
+    ) -> FimHealingDecision: ...
+
 _Value = TypeVar("_Value")
 
 class TokenSeqTrieNode(Generic[_Value]):
@@ -47,6 +141,52 @@ class TokenSeqTrieNode(Generic[_Value]):
     num_children: int
     value: _Value | None
 
+class PrefixCacheNodeId:
+    index: int
+    generation: int
+
+class PrefixMatch:
+    node: PrefixCacheNodeId | None
+    node_len: int
+    len: int
+    covering: PrefixCacheNodeId | None
+
+class PrefixCache(Generic[_Value]):
+    num_tokens: int
+
+    def __init__(self) -> None: ...
+    def __len__(self) -> int: ...
+    def __contains__(self, node: PrefixCacheNodeId) -> bool: ...
+    def parent(self, node: PrefixCacheNodeId) -> PrefixCacheNodeId | None: ...
+    def handle(self, node: PrefixCacheNodeId) -> _Value | None: ...
+    def ref_count(self, node: PrefixCacheNodeId) -> int | None: ...
+    def tokens(self, node: PrefixCacheNodeId) -> list[int] | None: ...
+    def children(self, node: PrefixCacheNodeId) -> list[PrefixCacheNodeId]: ...
+    def insert(
+        self, tokens: Sequence[int], handle: _Value
+    ) -> tuple[PrefixCacheNodeId, _Value | None]: ...
+    def match_prefix(self, tokens: Sequence[int]) -> PrefixMatch: ...
+    def pin(self, node: PrefixCacheNodeId) -> None: ...
+    def unpin(self, node: PrefixCacheNodeId) -> None: ...
+    def evict(self, max_tokens: int) -> list[_Value]: ...
+
+class AncestorIndex:
+    def __len__(self) -> int: ...
+    def depth(self, node: int) -> int | None: ...
+    def kth_ancestor(self, node: int, k: int) -> int | None: ...
+    def lca(self, a: int, b: int) -> int | None: ...
+
+class TokenSeqChunk:
+    lower: int
+    upper: int
+    visible_chunks: Sequence[int]
+
+class TokenSeqSegment:
+    parent: int
+    lower: int
+    upper: int
+    inputs: Sequence[int]
+
 class TokenSeqTrie(Generic[_Value]):
     tokens: Sequence[int]
     parents: Sequence[int]
@@ -55,13 +195,69 @@ class TokenSeqTrie(Generic[_Value]):
     depths: Sequence[int]
     num_children_seq: Sequence[int]
     values: Sequence[_Value | None]
+    input_nodes: Sequence[int | None]
+
+    def __len__(self) -> int: ...
+    def segments(self) -> list[TokenSeqSegment]: ...
+    def ancestor_mask(self, packed: bool = False) -> bytes: ...
+    def subtree_counts(self) -> list[int]: ...
+    # values are all ints within int64 or all floats, or else this raises
+    def subtree_reduce(
+        self, op: Literal["sum", "max", "min"]
+    ) -> list[int | float | None]: ...
+    def prefill_chunks(self, max_tokens: int) -> list[TokenSeqChunk]: ...
+    def build_ancestor_index(self) -> AncestorIndex: ...
+    def token_path(self, node: int) -> list[int]: ...
+    def position_ids(self, prefix_len: int = 0) -> bytes: ...
+
+class MutableTokenSeqTrieNodeId:
+    index: int
+    generation: int
+
+class TokenSeqTrieLayout:
+    node_ids: Sequence[MutableTokenSeqTrieNodeId]
+    parents: Sequence[int]
+    subtree_lower_seq: Sequence[int]
+    subtree_upper_seq: Sequence[int]
+    depths: Sequence[int]
+    tokens: Sequence[int]
+
+    def __len__(self) -> int: ...
 
+class MutableTokenSeqTrie(Generic[_Value]):
+    def __init__(self) -> None: ...
     def __len__(self) -> int: ...
+    def __contains__(self, node: MutableTokenSeqTrieNodeId) -> bool: ...
+    def parent(
+        self, node: MutableTokenSeqTrieNodeId
+    ) -> MutableTokenSeqTrieNodeId | None: ...
+    def token(self, node: MutableTokenSeqTrieNodeId) -> int | None: ...
+    def children(
+        self, node: MutableTokenSeqTrieNodeId | None = None
+    ) -> list[MutableTokenSeqTrieNodeId]: ...
+    def value(self, node: MutableTokenSeqTrieNodeId) -> _Value | None: ...
+    def set_value(
+        self, node: MutableTokenSeqTrieNodeId, value: _Value | None
+    ) -> _Value | None: ...
+    def add_child(
+        self, node: MutableTokenSeqTrieNodeId | None, token: int
+    ) -> MutableTokenSeqTrieNodeId: ...
+    def insert(
+        self, tokens: Sequence[int], value: _Value | None = None
+    ) -> MutableTokenSeqTrieNodeId | None: ...
+    def prune(self, node: MutableTokenSeqTrieNodeId) -> int: ...
+    def linearize(self) -> TokenSeqTrieLayout: ...
 
 def dfs_token_seq_trie(
     sequences: Sequence[Sequence[int]],
     values: Sequence[_Value],
+    value_policy: Literal["first", "last", "all"] = "last",
+    child_order: Literal["token_id", "insertion", "priority"] = "token_id",
+    priorities: Sequence[int] | None = None,
 ) -> tuple[TokenSeqTrie, int]: ...
 def dfs_token_seq_trie_as_nodes(
     sequences_and_values: Sequence[tuple[Sequence[int], _Value]],
+    value_policy: Literal["first", "last", "all"] = "last",
+    child_order: Literal["token_id", "insertion", "priority"] = "token_id",
+    priorities: Sequence[int] | None = None,
 ) -> tuple[Sequence[TokenSeqTrieNode[_Value]], int]: ...
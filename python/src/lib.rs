@@ -1,6 +1,15 @@
+mod mutable_token_seq_trie;
+mod prefix_cache;
 mod prefix_dfs;
 
-use ::mtc_token_healing::{SortedTokenRange, TokenId, VocabPrefixAutomaton};
+use ::mtc_token_healing::{
+    AncestorIndex, FimHealingDecision, FimSuffixDecision, HealingDecision, HealingPolicy,
+    HealingPreference, MutableTokenSeqTrieNodeId, PrefixCacheNodeId, PrefixMatch, SortedTokenRange,
+    TokenId, TokenSeqChunk, TokenSeqSegment, TokenSeqTrieLayout, Utf8ClassColumns, Utf8Constraint,
+    VocabPrefixAutomaton,
+};
+use mutable_token_seq_trie::MutableTokenSeqTrie;
+use prefix_cache::PrefixCache;
 use prefix_dfs::TokenSeqTrie;
 use pyo3::prelude::*;
 
@@ -10,8 +19,24 @@ use crate::prefix_dfs::{TokenSeqTrieNode, dfs_token_seq_trie_py, dfs_token_seq_t
 fn mtc_token_healing(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<SortedTokenRange>()?;
     m.add_class::<VocabPrefixAutomaton>()?;
+    m.add_class::<Utf8ClassColumns>()?;
+    m.add_class::<Utf8Constraint>()?;
+    m.add_class::<HealingPreference>()?;
+    m.add_class::<HealingPolicy>()?;
+    m.add_class::<HealingDecision>()?;
+    m.add_class::<FimSuffixDecision>()?;
+    m.add_class::<FimHealingDecision>()?;
+    m.add_class::<PrefixCacheNodeId>()?;
+    m.add_class::<PrefixMatch>()?;
+    m.add_class::<PrefixCache>()?;
+    m.add_class::<TokenSeqChunk>()?;
+    m.add_class::<TokenSeqSegment>()?;
     m.add_class::<TokenSeqTrieNode>()?;
     m.add_class::<TokenSeqTrie>()?;
+    m.add_class::<TokenSeqTrieLayout>()?;
+    m.add_class::<AncestorIndex>()?;
+    m.add_class::<MutableTokenSeqTrieNodeId>()?;
+    m.add_class::<MutableTokenSeqTrie>()?;
     m.add_function(wrap_pyfunction!(dfs_token_seq_trie_py, m)?)?;
     m.add_function(wrap_pyfunction!(dfs_token_seq_trie_soa_py, m)?)?;
     Ok(())
@@ -0,0 +1,82 @@
+use mtc_token_healing::{
+    MutableTokenSeqTrie as GenericMutableTokenSeqTrie, MutableTokenSeqTrieNodeId,
+    TokenSeqTrieLayout,
+};
+use pyo3::exceptions::PyValueError;
+use pyo3::{Py, PyAny, PyResult, Python, pyclass, pymethods};
+
+use crate::TokenId;
+
+#[derive(Debug, Default)]
+#[pyclass]
+pub struct MutableTokenSeqTrie {
+    inner: GenericMutableTokenSeqTrie<Py<PyAny>>,
+}
+
+#[pymethods]
+impl MutableTokenSeqTrie {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn __contains__(&self, node: MutableTokenSeqTrieNodeId) -> bool {
+        self.inner.contains(node)
+    }
+
+    fn parent(&self, node: MutableTokenSeqTrieNodeId) -> Option<MutableTokenSeqTrieNodeId> {
+        self.inner.parent(node)
+    }
+
+    fn token(&self, node: MutableTokenSeqTrieNodeId) -> Option<TokenId> {
+        self.inner.token(node)
+    }
+
+    #[pyo3(signature=(node=None))]
+    fn children(&self, node: Option<MutableTokenSeqTrieNodeId>) -> Vec<MutableTokenSeqTrieNodeId> {
+        self.inner.children(node).collect()
+    }
+
+    fn value(&self, py: Python<'_>, node: MutableTokenSeqTrieNodeId) -> Option<Py<PyAny>> {
+        self.inner.value(node).map(|v| v.clone_ref(py))
+    }
+
+    fn set_value(
+        &mut self,
+        node: MutableTokenSeqTrieNodeId,
+        value: Option<Py<PyAny>>,
+    ) -> Option<Py<PyAny>> {
+        self.inner.set_value(node, value)
+    }
+
+    fn add_child(
+        &mut self,
+        node: Option<MutableTokenSeqTrieNodeId>,
+        token: TokenId,
+    ) -> PyResult<MutableTokenSeqTrieNodeId> {
+        self.inner
+            .add_child(node, token)
+            .ok_or_else(|| PyValueError::new_err(format!("no such node: {node:?}")))
+    }
+
+    #[pyo3(signature=(tokens, value=None))]
+    fn insert(
+        &mut self,
+        tokens: Vec<TokenId>,
+        value: Option<Py<PyAny>>,
+    ) -> Option<MutableTokenSeqTrieNodeId> {
+        self.inner.insert(&tokens, value)
+    }
+
+    fn prune(&mut self, py: Python<'_>, node: MutableTokenSeqTrieNodeId) -> usize {
+        py.detach(|| self.inner.prune(node))
+    }
+
+    fn linearize(&self, py: Python<'_>) -> TokenSeqTrieLayout {
+        py.detach(|| self.inner.linearize())
+    }
+}
@@ -0,0 +1,86 @@
+use mtc_token_healing::{PrefixCache as GenericPrefixCache, PrefixCacheNodeId, PrefixMatch};
+use pyo3::exceptions::PyValueError;
+use pyo3::{Py, PyAny, PyResult, Python, pyclass, pymethods};
+
+use crate::TokenId;
+
+#[derive(Debug, Default)]
+#[pyclass]
+pub struct PrefixCache {
+    inner: GenericPrefixCache<Py<PyAny>>,
+}
+
+#[pymethods]
+impl PrefixCache {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    #[getter]
+    fn num_tokens(&self) -> usize {
+        self.inner.num_tokens()
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.num_nodes()
+    }
+
+    fn __contains__(&self, node: PrefixCacheNodeId) -> bool {
+        self.inner.contains(node)
+    }
+
+    fn parent(&self, node: PrefixCacheNodeId) -> Option<PrefixCacheNodeId> {
+        self.inner.parent(node)
+    }
+
+    fn handle(&self, py: Python<'_>, node: PrefixCacheNodeId) -> Option<Py<PyAny>> {
+        self.inner.handle(node).map(|h| h.clone_ref(py))
+    }
+
+    fn ref_count(&self, node: PrefixCacheNodeId) -> Option<usize> {
+        self.inner.ref_count(node)
+    }
+
+    fn tokens(&self, node: PrefixCacheNodeId) -> Option<Vec<TokenId>> {
+        self.inner.tokens(node)
+    }
+
+    fn children(&self, node: PrefixCacheNodeId) -> Vec<PrefixCacheNodeId> {
+        self.inner.children(node).collect()
+    }
+
+    /// Returns the node of the tokens and the handle it replaces.
+    fn insert(
+        &mut self,
+        py: Python<'_>,
+        tokens: Vec<TokenId>,
+        handle: Py<PyAny>,
+    ) -> PyResult<(PrefixCacheNodeId, Option<Py<PyAny>>)> {
+        py.detach(|| self.inner.insert(&tokens, handle))
+            .ok_or_else(|| PyValueError::new_err("cannot insert an empty sequence"))
+    }
+
+    fn match_prefix(&mut self, py: Python<'_>, tokens: Vec<TokenId>) -> PrefixMatch {
+        py.detach(|| self.inner.match_prefix(&tokens))
+    }
+
+    fn pin(&mut self, node: PrefixCacheNodeId) -> PyResult<()> {
+        self.inner
+            .pin(node)
+            .then_some(())
+            .ok_or_else(|| PyValueError::new_err(format!("no such node: {node:?}")))
+    }
+
+    fn unpin(&mut self, node: PrefixCacheNodeId) -> PyResult<()> {
+        self.inner
+            .unpin(node)
+            .then_some(())
+            .ok_or_else(|| PyValueError::new_err(format!("node {node:?} is not pinned")))
+    }
+
+    /// Returns the handles of evicted nodes.
+    fn evict(&mut self, py: Python<'_>, max_tokens: usize) -> Vec<Py<PyAny>> {
+        py.detach(|| self.inner.evict(max_tokens))
+    }
+}
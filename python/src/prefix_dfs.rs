@@ -1,10 +1,20 @@
 use std::borrow::Cow;
-use std::convert::Infallible;
+use std::ops::Add;
 
-use derive_more::{From, Into};
-use general_sam::{BTreeTransTable, TravelEvent, Trie, TrieNodeAlike};
+use derive_more::Into;
 use itertools::{Itertools, multiunzip};
-use pyo3::{Py, PyAny, PyErr, PyResult, Python, pyclass, pyfunction, pymethods};
+use mtc_token_healing::{
+    AncestorIndex, TokenSeqChildOrder, TokenSeqChunk, TokenSeqSegment,
+    TokenSeqTrie as GenericTokenSeqTrie, TokenSeqTrieNode as GenericTokenSeqTrieNode,
+    TokenSeqTrieOptions, TokenSeqValuePolicy, ancestor_mask, packed_ancestor_mask, prefill_chunks,
+    reduce_subtrees, segments, token_path,
+};
+use pyo3::exceptions::{PyIndexError, PyTypeError, PyValueError};
+use pyo3::types::PyAnyMethods;
+use pyo3::types::{PyBytes, PyFloat, PyList};
+use pyo3::{
+    Bound, IntoPyObject, Py, PyAny, PyErr, PyResult, Python, pyclass, pyfunction, pymethods,
+};
 
 use crate::TokenId;
 
@@ -50,7 +60,7 @@ impl TokenSeqTrieNode {
     }
 }
 
-#[derive(Debug, From)]
+#[derive(Debug)]
 #[pyclass(get_all, set_all, generic)]
 pub struct TokenSeqTrie {
     pub parents: Vec<usize>,
@@ -60,6 +70,7 @@ pub struct TokenSeqTrie {
     pub num_children_seq: Vec<usize>,
     pub tokens: Vec<TokenId>,
     pub values: Vec<Option<Py<PyAny>>>,
+    pub input_nodes: Vec<Option<usize>>,
 }
 
 #[pymethods]
@@ -73,6 +84,7 @@ impl TokenSeqTrie {
             num_children_seq,
             tokens,
             values,
+            input_nodes,
         } = self;
         let values = values.iter().map(|opt| {
             opt.as_ref()
@@ -93,154 +105,306 @@ impl TokenSeqTrie {
                 subtree_upper_seq={subtree_upper_seq:?}, \
                 depths={depths:?}, \
                 num_children_seq={num_children_seq:?}, \
-                values=[{values_repr}])",
+                values=[{values_repr}], \
+                input_nodes={input_nodes:?})",
         ))
     }
 
     fn __len__(&self) -> usize {
         self.parents.len()
     }
-}
-
-#[derive(Debug)]
-struct TokenSeqInput<'a> {
-    pub tokens: Cow<'a, [TokenId]>,
-    pub value: Option<Py<PyAny>>,
-}
 
-fn dfs_token_seq_trie(inputs: Vec<TokenSeqInput>) -> Vec<TokenSeqTrieNode> {
-    let (trie, seq_last_trie_node_ids) = {
-        let mut trie = Trie::<BTreeTransTable<_>>::default();
-        let mut seq_last_node_ids = vec![0; inputs.len()];
-        for (i, seq) in inputs.iter().enumerate() {
-            seq_last_node_ids[i] = trie.insert(seq.tokens.iter().copied());
-        }
-        (trie, seq_last_node_ids)
-    };
+    /// The radix-compressed view of the trie with segments in DFS order.
+    fn segments(&self, py: Python<'_>) -> PyResult<Vec<TokenSeqSegment>> {
+        py.detach(|| segments(&self.parents, &self.num_children_seq, &self.input_nodes))
+            .ok_or_else(|| PyValueError::new_err("parents must precede children"))
+    }
 
-    let mut dfs_order = Vec::new();
-    let mut rank = vec![None; trie.num_of_nodes()];
-    let res = trie
-        .get_root_state()
-        .dfs_travel(|event| -> Result<(), Infallible> {
-            match event {
-                TravelEvent::PushRoot(_) => {}
-                TravelEvent::Push(node, _, token) => {
-                    let dfs_order_id = dfs_order.len();
-                    rank[node.node_id] = Some(dfs_order_id);
-                    let parent = node
-                        .get_node()
-                        .and_then(|n| rank[n.get_parent()])
-                        .unwrap_or(dfs_order_id);
-                    dfs_order.push(TokenSeqTrieNode {
-                        parent,
-                        subtree_lower: dfs_order_id,
-                        subtree_upper: dfs_order_id,
-                        depth: 0,
-                        num_children: 0,
-                        token,
-                        value: None,
-                    });
-                }
-                TravelEvent::Pop(node, _) => {
-                    if let Some(id) = rank[node.node_id]
-                        && let Some(parent) = node.get_node().and_then(|n| rank[n.get_parent()])
-                    {
-                        dfs_order[parent].subtree_upper = dfs_order[id].subtree_upper;
-                        dfs_order[parent].num_children += 1;
-                    }
-                }
+    /// Row-major ancestor-or-self mask, one byte per entry
+    /// or rows packed in little bit order.
+    #[pyo3(signature=(packed=false))]
+    fn ancestor_mask<'py>(&self, py: Python<'py>, packed: bool) -> PyResult<Bound<'py, PyBytes>> {
+        let mask = py.detach(|| {
+            if packed {
+                packed_ancestor_mask(&self.parents)
+            } else {
+                ancestor_mask::<u8>(&self.parents)
             }
-            Ok(())
         });
-    match res {
-        Ok(()) => {}
-        Err(e) => match e {},
+        let mask = mask.ok_or_else(|| PyValueError::new_err("parents must precede children"))?;
+        Ok(PyBytes::new(py, &mask))
     }
 
-    for i in 0..dfs_order.len() {
-        let parent = dfs_order[i].parent;
-        if parent == i {
-            continue;
+    /// Number of inputs ending within the subtree of each node.
+    fn subtree_counts(&self, py: Python<'_>) -> PyResult<Vec<usize>> {
+        let mut counts = vec![Some(0); self.parents.len()];
+        for &node in self.input_nodes.iter().flatten() {
+            let count = counts
+                .get_mut(node)
+                .and_then(Option::as_mut)
+                .ok_or_else(|| PyIndexError::new_err(format!("no such node: {node}")))?;
+            *count += 1;
         }
-        dfs_order[i].depth = dfs_order[parent].depth + 1;
+        let counts = py
+            .detach(|| reduce_subtrees(&self.parents, counts, |a, b| a + b))
+            .ok_or_else(|| PyValueError::new_err("parents must precede children"))?;
+        Ok(counts.into_iter().map(Option::unwrap_or_default).collect())
     }
 
-    for (input, node_id) in inputs.into_iter().zip(seq_last_trie_node_ids) {
-        if let Some(id) = rank[node_id] {
-            dfs_order[id].value = input.value;
+    /// Reduces numeric values within the subtree of each node,
+    /// `None` for subtrees without values.
+    ///
+    /// Values are reduced as ints fitting in int64 unless any of them is a float,
+    /// in which case all of them have to be.
+    fn subtree_reduce(&self, py: Python<'_>, op: &str) -> PyResult<Vec<Option<Py<PyAny>>>> {
+        let (int_op, float_op) = (numeric_op::<i128>(op)?, numeric_op::<f64>(op)?);
+        let is_float = |v: &Py<PyAny>| v.bind(py).is_instance_of::<PyFloat>();
+        if !self.values.iter().flatten().any(is_float) {
+            let ints = self
+                .values
+                .iter()
+                .map(|v| v.as_ref().map(|v| v.extract::<i64>(py)).transpose())
+                .map_ok(|v| v.map(i128::from))
+                .collect::<PyResult<Vec<_>>>()?;
+            return reduce_numeric(py, &self.parents, ints, int_op)?
+                .into_iter()
+                .map(|v| {
+                    v.map(|v| Ok(v.into_pyobject(py)?.into_any().unbind()))
+                        .transpose()
+                })
+                .collect();
+        }
+        if !self.values.iter().flatten().all(is_float) {
+            return Err(PyTypeError::new_err(
+                "values must be either all ints or all floats",
+            ));
         }
+        let floats = self
+            .values
+            .iter()
+            .map(|v| v.as_ref().map(|v| v.extract::<f64>(py)).transpose())
+            .collect::<PyResult<Vec<_>>>()?;
+        reduce_numeric(py, &self.parents, floats, float_op)?
+            .into_iter()
+            .map(|v| {
+                v.map(|v| Ok(v.into_pyobject(py)?.into_any().unbind()))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    /// Splits nodes in DFS order into chunks of at most `max_tokens` nodes.
+    fn prefill_chunks(&self, py: Python<'_>, max_tokens: usize) -> PyResult<Vec<TokenSeqChunk>> {
+        if max_tokens == 0 {
+            return Err(PyValueError::new_err("max_tokens must be positive"));
+        }
+        py.detach(|| prefill_chunks(&self.parents, max_tokens))
+            .ok_or_else(|| PyValueError::new_err("parents must precede children"))
+    }
+
+    fn build_ancestor_index(&self, py: Python<'_>) -> PyResult<AncestorIndex> {
+        py.detach(|| AncestorIndex::new(&self.parents))
+            .ok_or_else(|| PyValueError::new_err("parents must precede children"))
     }
 
-    #[cfg(debug_assertions)]
-    for (i, node) in dfs_order.iter().enumerate() {
-        debug_assert!(node.subtree_lower <= node.subtree_upper);
-        debug_assert!(node.subtree_lower == i);
-        debug_assert!(node.parent <= i);
-        if node.parent < node.subtree_lower {
-            debug_assert!(node.parent != i);
-            let parent = &dfs_order[node.parent];
-            debug_assert!(parent.subtree_lower < node.subtree_lower);
-            debug_assert!(parent.subtree_upper >= node.subtree_lower);
-        } else {
-            debug_assert!(node.parent == i);
+    /// Tokens from the root to the node.
+    fn token_path(&self, node: usize) -> PyResult<Vec<TokenId>> {
+        if node >= self.parents.len() || self.tokens.len() != self.parents.len() {
+            return Err(PyIndexError::new_err(format!("no such node: {node}")));
         }
+        token_path(&self.parents, &self.tokens, node)
+            .ok_or_else(|| PyValueError::new_err("parents must precede children"))
     }
 
-    dfs_order
+    /// Depths offset by `prefix_len`, as native-endian int64.
+    #[pyo3(signature=(prefix_len=0))]
+    fn position_ids<'py>(&self, py: Python<'py>, prefix_len: usize) -> Bound<'py, PyBytes> {
+        let ids: Vec<u8> = self
+            .depths
+            .iter()
+            .flat_map(|&depth| ((depth + prefix_len) as i64).to_ne_bytes())
+            .collect();
+        PyBytes::new(py, &ids)
+    }
 }
 
-#[pyfunction(name = "dfs_token_seq_trie_as_nodes")]
-pub fn dfs_token_seq_trie_py<'py>(
-    py: Python<'py>,
+fn numeric_op<T: Copy + PartialOrd + Add<Output = T>>(op: &str) -> PyResult<fn(T, T) -> T> {
+    match op {
+        "sum" => Ok(|a, b| a + b),
+        "max" => Ok(|a, b| if b > a { b } else { a }),
+        "min" => Ok(|a, b| if b < a { b } else { a }),
+        _ => Err(PyValueError::new_err(format!("unknown reduction: {op:?}"))),
+    }
+}
+
+fn reduce_numeric<T: Clone + Send>(
+    py: Python<'_>,
+    parents: &[usize],
+    values: Vec<Option<T>>,
+    reduce: fn(T, T) -> T,
+) -> PyResult<Vec<Option<T>>> {
+    py.detach(|| reduce_subtrees(parents, values, reduce))
+        .ok_or_else(|| PyValueError::new_err("parents must precede children"))
+}
+
+struct BuiltTrie {
+    nodes: Vec<TokenSeqTrieNode>,
+    input_nodes: Vec<Option<usize>>,
+    prefill_chain_len: usize,
+}
+
+fn build_trie(
+    py: Python<'_>,
     inputs: Vec<(Vec<TokenId>, Option<Py<PyAny>>)>,
-) -> (Vec<TokenSeqTrieNode>, usize) {
+    value_policy: &str,
+    child_order: &str,
+    priorities: Option<Vec<i64>>,
+) -> PyResult<BuiltTrie> {
     debug_assert!(
         inputs
             .iter()
             .all(|(_, o)| o.as_ref().is_none_or(|v| !v.is_none(py)))
     );
 
-    py.detach(|| {
-        let inputs = inputs
-            .into_iter()
-            .map(|(s, v)| TokenSeqInput {
-                tokens: Cow::Owned(s),
-                value: v,
-            })
-            .collect();
+    let policy = match value_policy {
+        "first" => TokenSeqValuePolicy::First,
+        "last" | "all" => TokenSeqValuePolicy::Last,
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "unknown value policy: {value_policy:?}"
+            )));
+        }
+    };
+    let child_order = match (child_order, priorities) {
+        ("token_id", None) => TokenSeqChildOrder::TokenId,
+        ("insertion", None) => TokenSeqChildOrder::Insertion,
+        ("priority", Some(priorities)) => TokenSeqChildOrder::Priority(priorities),
+        ("priority", None) => {
+            return Err(PyValueError::new_err(
+                "priorities are required for the priority child order",
+            ));
+        }
+        ("token_id" | "insertion", Some(_)) => {
+            return Err(PyValueError::new_err(
+                "priorities are only used by the priority child order",
+            ));
+        }
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "unknown child order: {child_order:?}"
+            )));
+        }
+    };
+    let options = TokenSeqTrieOptions {
+        value_policy: policy,
+        child_order,
+    };
+    let (sequences, mut values): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
 
-        let nodes = dfs_token_seq_trie(inputs);
+    // values of nodes are indices of inputs until converted back
+    let trie = py.detach(|| {
+        let indexed = sequences
+            .iter()
+            .zip(&values)
+            .enumerate()
+            .map(|(i, (seq, value))| (seq, value.as_ref().map(|_| i)));
+        GenericTokenSeqTrie::with_options(indexed, &options)
+    });
 
-        let prefill_chain_len = {
-            let mut res = 0;
-            while res < nodes.len() {
-                let node = &nodes[res];
-                if node.num_children == 1 && node.value.is_none() {
-                    res += 1;
-                    continue;
+    let prefill_chain_len = trie.prefill_chain_len();
+    let input_nodes = trie.input_nodes().to_vec();
+    let nodes = (0..trie.len())
+        .map(|i| {
+            let value = if value_policy == "all" {
+                let all = trie
+                    .inputs_of(i)
+                    .iter()
+                    .filter_map(|&j| values[j].take())
+                    .collect::<Vec<_>>();
+                if all.is_empty() {
+                    None
+                } else {
+                    Some(PyList::new(py, all)?.into_any().unbind())
                 }
-                break;
-            }
-            res
-        };
-
-        (nodes, prefill_chain_len)
+            } else {
+                trie.nodes()[i].value.and_then(|j| values[j].take())
+            };
+            let GenericTokenSeqTrieNode {
+                parent,
+                subtree_lower,
+                subtree_upper,
+                depth,
+                num_children,
+                token,
+                ..
+            } = trie.nodes()[i];
+            Ok(TokenSeqTrieNode {
+                parent,
+                subtree_lower,
+                subtree_upper,
+                depth,
+                num_children,
+                token,
+                value,
+            })
+        })
+        .collect::<PyResult<_>>()?;
+    Ok(BuiltTrie {
+        nodes,
+        input_nodes,
+        prefill_chain_len,
     })
 }
 
+#[pyfunction(name = "dfs_token_seq_trie_as_nodes")]
+#[pyo3(signature=(inputs, value_policy="last", child_order="token_id", priorities=None))]
+pub fn dfs_token_seq_trie_py<'py>(
+    py: Python<'py>,
+    inputs: Vec<(Vec<TokenId>, Option<Py<PyAny>>)>,
+    value_policy: &str,
+    child_order: &str,
+    priorities: Option<Vec<i64>>,
+) -> PyResult<(Vec<TokenSeqTrieNode>, usize)> {
+    let BuiltTrie {
+        nodes,
+        prefill_chain_len,
+        ..
+    } = build_trie(py, inputs, value_policy, child_order, priorities)?;
+    Ok((nodes, prefill_chain_len))
+}
+
 #[pyfunction(name = "dfs_token_seq_trie")]
+#[pyo3(signature=(sequences, values, value_policy="last", child_order="token_id", priorities=None))]
 pub fn dfs_token_seq_trie_soa_py<'py>(
     py: Python<'py>,
     sequences: Vec<Vec<TokenId>>,
     values: Vec<Option<Py<PyAny>>>,
-) -> (TokenSeqTrie, usize) {
-    let (res, prefill_chain_len) =
-        dfs_token_seq_trie_py(py, sequences.into_iter().zip(values).collect());
-    py.detach(|| {
-        let soa = TokenSeqTrie::from(multiunzip(
-            res.into_iter().map(Into::<(_, _, _, _, _, _, _)>::into),
-        ));
-        (soa, prefill_chain_len)
-    })
+    value_policy: &str,
+    child_order: &str,
+    priorities: Option<Vec<i64>>,
+) -> PyResult<(TokenSeqTrie, usize)> {
+    let BuiltTrie {
+        nodes,
+        input_nodes,
+        prefill_chain_len,
+    } = build_trie(
+        py,
+        sequences.into_iter().zip(values).collect(),
+        value_policy,
+        child_order,
+        priorities,
+    )?;
+    let (parents, subtree_lower_seq, subtree_upper_seq, depths, num_children_seq, tokens, values) =
+        multiunzip(nodes.into_iter().map(Into::<(_, _, _, _, _, _, _)>::into));
+    let soa = TokenSeqTrie {
+        parents,
+        subtree_lower_seq,
+        subtree_upper_seq,
+        depths,
+        num_children_seq,
+        tokens,
+        values,
+        input_nodes,
+    };
+    Ok((soa, prefill_chain_len))
 }
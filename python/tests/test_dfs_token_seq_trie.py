@@ -1,4 +1,6 @@
-from mtc_token_healing import SortedTokenRange, dfs_token_seq_trie
+from array import array
+
+from mtc_token_healing import MutableTokenSeqTrie, SortedTokenRange, dfs_token_seq_trie
 
 
 def test_dfs_token_seq_trie():
@@ -55,14 +57,233 @@ def run_test_dfs_token_seq_trie(tokens_seq, pred_ranges):
         assert pred_ranges[tokens_seq.index(seq)] == tree.values[q]
         assert tree.depths[q] + 1 == len(seq)
 
-    for q in range(len(tree.values)):
+    n = len(tree)
+    mask = tree.ancestor_mask()
+    packed = tree.ancestor_mask(packed=True)
+    stride = (n + 7) // 8
+    assert len(mask) == n * n
+    assert len(packed) == n * stride
+    for q in range(n):
         masks = [
             k <= q and tree.subtree_upper_seq[k] >= tree.subtree_upper_seq[q]
-            for k in range(len(tree.values))
+            for k in range(n)
         ]
         print("".join(map(str, map(int, masks))))
+        assert list(mask[q * n : (q + 1) * n]) == list(map(int, masks))
+        row = packed[q * stride : (q + 1) * stride]
+        assert [row[k // 8] >> (k % 8) & 1 for k in range(n)] == list(map(int, masks))
+
+    index = tree.build_ancestor_index()
+    for q in range(n):
+        path = tree.token_path(q)
+        assert len(path) == tree.depths[q] + 1
+        for k in range(len(path)):
+            assert tree.token_path(index.kth_ancestor(q, k)) == path[: len(path) - k]
+        assert index.kth_ancestor(q, len(path)) is None
+        for r in range(n):
+            common = [j for j in range(n) if mask[q * n + j] and mask[r * n + j]]
+            assert index.lca(q, r) == max(common, key=lambda j: tree.depths[j])
+
+    for max_tokens in range(1, n + 2):
+        chunks = tree.prefill_chunks(max_tokens)
+        assert [(c.lower, c.upper) for c in chunks] == [
+            (i, min(i + max_tokens, n)) for i in range(0, n, max_tokens)
+        ]
+        for c, chunk in enumerate(chunks):
+            expected = {
+                j // max_tokens
+                for i in range(chunk.lower, chunk.upper)
+                for j in range(n)
+                if mask[i * n + j]
+            } - {c}
+            assert chunk.visible_chunks == sorted(expected)
+
+    assert array("q", tree.position_ids()).tolist() == tree.depths
+    assert array("q", tree.position_ids(prefix_len=5)).tolist() == [
+        d + 5 for d in tree.depths
+    ]
+
+
+def test_dfs_trie_duplicate_sequences():
+    tokens_seq = [[1, 2], [1, 2, 3], [1, 2], [], [1, 2]]
+    values = ["a", "b", "c", "d", None]
+
+    tree, _ = dfs_token_seq_trie(tokens_seq, values)
+    assert tree.input_nodes == [1, 2, 1, None, 1]
+    assert tree.values == [None, "c", "b"]
+
+    tree, _ = dfs_token_seq_trie(tokens_seq, values, value_policy="first")
+    assert tree.values == [None, "a", "b"]
+
+    tree, _ = dfs_token_seq_trie(tokens_seq, values, value_policy="all")
+    assert tree.values == [None, ["a", "c"], ["b"]]
+
+    try:
+        dfs_token_seq_trie(tokens_seq, values, value_policy="middle")
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError")
+
+
+def test_dfs_trie_segments():
+    tokens_seq = [[3, 9, 1, 10, 9, 6], [3, 9, 1, 10, 9, 5], [3, 9, 1, 11], [3, 9]]
+    tree, _ = dfs_token_seq_trie(tokens_seq, list(range(len(tokens_seq))))
+    segments = [(s.parent, s.lower, s.upper, s.inputs) for s in tree.segments()]
+    assert segments == [
+        (0, 0, 2, [0, 1, 2, 3]),
+        (0, 2, 3, [0, 1, 2]),
+        (1, 3, 5, [0, 1]),
+        (2, 5, 6, [1]),
+        (2, 6, 7, [0]),
+        (1, 7, 8, [2]),
+    ]
+    for s in tree.segments():
+        span = tree.tokens[s.lower : s.upper]
+        start = tree.depths[s.lower]
+        for i in s.inputs:
+            assert tokens_seq[i][start : start + len(span)] == span
+        assert all(tree.num_children_seq[i] == 1 for i in range(s.lower, s.upper - 1))
+
+
+def test_dfs_trie_child_order():
+    tokens_seq = [[5, 1], [3, 2], [5, 0], [3]]
+    values = ["a", "b", "c", "d"]
+
+    tree, _ = dfs_token_seq_trie(tokens_seq, values)
+    assert tree.tokens == [3, 2, 5, 0, 1]
+
+    tree, _ = dfs_token_seq_trie(tokens_seq, values, child_order="insertion")
+    assert tree.tokens == [5, 1, 0, 3, 2]
+    assert tree.parents == [0, 0, 0, 3, 3]
+    assert tree.subtree_upper_seq == [2, 1, 2, 4, 4]
+    assert tree.values == [None, "a", "c", "d", "b"]
+
+    tree, _ = dfs_token_seq_trie(
+        tokens_seq, values, child_order="priority", priorities=[0, 5, 1, -2]
+    )
+    assert tree.tokens == [3, 2, 5, 1, 0]
+    for seq, node in zip(tokens_seq, tree.input_nodes):
+        assert tree.token_path(node) == seq
+
+    for kwargs in [
+        {"child_order": "priority"},
+        {"child_order": "insertion", "priorities": [0]},
+        {"child_order": "random"},
+    ]:
+        try:
+            dfs_token_seq_trie(tokens_seq, values, **kwargs)
+        except ValueError:
+            pass
+        else:
+            raise AssertionError("expected ValueError")
+
+
+def test_dfs_trie_subtree_reduce():
+    tokens_seq = [
+        [3, 9, 1, 10, 9, 6],
+        [3, 9, 1, 10, 9, 5],
+        [3, 9, 1, 11],
+        [3, 9],
+        [3, 9],
+    ]
+    for values in [[4, -2, 7, 1, 3], [0.5, 2.0, -1.5, 3.0, None]]:
+        tree, _ = dfs_token_seq_trie(tokens_seq, values, value_policy="first")
+        n = len(tree)
+        subtrees = [
+            [
+                tree.values[j]
+                for j in range(tree.subtree_lower_seq[i], tree.subtree_upper_seq[i] + 1)
+                if tree.values[j] is not None
+            ]
+            for i in range(n)
+        ]
+        for op, reduce in [("sum", sum), ("max", max), ("min", min)]:
+            expected = [reduce(s) if s else None for s in subtrees]
+            assert tree.subtree_reduce(op) == expected
+        assert all(type(v) is type(values[0]) for v in tree.subtree_reduce("sum"))
+
+        counts = [
+            sum(
+                node is not None
+                and tree.subtree_lower_seq[i] <= node <= tree.subtree_upper_seq[i]
+                for node in tree.input_nodes
+            )
+            for i in range(n)
+        ]
+        assert tree.subtree_counts() == counts
+    assert tree.subtree_counts()[:2] == [5, 5]
+
+    for values, error in [
+        ([1, 2.0, 3, 4, 5], TypeError),
+        ([2**70, 1, 2, 3, 4], OverflowError),
+    ]:
+        tree, _ = dfs_token_seq_trie(tokens_seq, values)
+        try:
+            tree.subtree_reduce("sum")
+        except error:
+            pass
+        else:
+            raise AssertionError(f"expected {error.__name__}")
+
+    tree, _ = dfs_token_seq_trie(tokens_seq, ["a"] * len(tokens_seq))
+    for op, error in [("sum", TypeError), ("avg", ValueError)]:
+        try:
+            tree.subtree_reduce(op)
+        except error:
+            pass
+        else:
+            raise AssertionError(f"expected {error.__name__}")
+
+
+def test_mutable_token_seq_trie():
+    tokens_seq = [[3, 9, 1, 10, 9, 6], [3, 9, 1, 10, 9, 5], [3, 9, 1, 11], [3, 9]]
+    trie = MutableTokenSeqTrie()
+    leaves = [trie.insert(seq, i) for i, seq in enumerate(tokens_seq)]
+    tree, _ = dfs_token_seq_trie(tokens_seq, list(range(len(tokens_seq))))
+
+    layout = trie.linearize()
+    assert len(layout) == len(tree) == len(trie)
+    assert layout.tokens == tree.tokens
+    assert layout.parents == tree.parents
+    assert layout.subtree_upper_seq == tree.subtree_upper_seq
+    assert layout.depths == tree.depths
+    assert [trie.value(i) for i in layout.node_ids] == tree.values
+
+    branch = trie.parent(trie.parent(leaves[0]))
+    assert trie.token(branch) == 10
+    assert trie.prune(branch) == 4
+    beam = trie.add_child(trie.parent(leaves[2]), 12)
+    assert trie.children(trie.parent(beam)) == [leaves[2], beam]
+    assert trie.linearize().tokens == [3, 9, 1, 11, 12]
+    try:
+        trie.add_child(leaves[0], 1)
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError")
+
+
+def test_mutable_token_seq_trie_stale_ids():
+    trie = MutableTokenSeqTrie()
+    a = trie.insert([1, 2])
+    b = trie.insert([1, 3])
+    assert trie.prune(b) == 1
+    c = trie.add_child(trie.parent(a), 4)
+    assert c.index == b.index
+    assert c != b
+    assert b not in trie
+    assert trie.token(b) is None
+    assert trie.prune(b) == 0
+    assert trie.token(c) == 4
 
 
 if __name__ == "__main__":
     test_dfs_token_seq_trie()
     test_dfs_trie_value_on_prefix_chain()
+    test_dfs_trie_duplicate_sequences()
+    test_dfs_trie_segments()
+    test_dfs_trie_child_order()
+    test_dfs_trie_subtree_reduce()
+    test_mutable_token_seq_trie()
+    test_mutable_token_seq_trie_stale_ids()
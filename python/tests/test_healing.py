@@ -0,0 +1,59 @@
+from mtc_token_healing import HealingPolicy, HealingPreference, VocabPrefixAutomaton
+
+
+def test_healing_policy():
+    vocab = [b"hello", b" wor", b"l", b" world", b"world", b"lo world"]
+    automaton = VocabPrefixAutomaton(vocab)
+    tokens = [0, 1, 2]  # "hello" + " wor" + "l"
+
+    decision = HealingPolicy().decide(automaton, tokens)
+    assert decision is not None
+    assert decision.removed_bytes == b"lo worl"
+    assert decision.num_removed_tokens == 3
+    assert decision.split_offset == 3
+    order = automaton.get_order()
+    rank_range = decision.rank_range
+    assert [order[k] for k in range(rank_range.lower, rank_range.upper)] == [5]
+
+    policy = HealingPolicy(heal_through_whitespace=False)
+    assert policy.decide(automaton, tokens).removed_bytes == b" worl"
+
+    policy.prefer = HealingPreference.Shallowest
+    assert policy.decide(automaton, tokens).removed_bytes == b"l"
+
+    policy = HealingPolicy.conservative()
+    assert policy.max_tokens == 1
+    assert policy.decide(automaton, tokens).removed_bytes == b"l"
+    assert HealingPolicy(max_candidates=0).decide(automaton, tokens) is None
+
+
+def test_healing_barriers():
+    vocab = [b"<|user|>", b"hi", b">hi", b"<|assistant|>"]
+    automaton = VocabPrefixAutomaton(vocab)
+    tokens = [0, 1]
+
+    assert HealingPolicy().decide(automaton, tokens).removed_bytes == b">hi"
+    assert HealingPolicy().decide(automaton, tokens, min_index=1).removed_bytes == b"hi"
+
+    policy = HealingPolicy(barrier_token_ids={0, 3})
+    assert policy.barrier_token_ids == {0, 3}
+    assert policy.decide(automaton, tokens).removed_bytes == b"hi"
+    assert policy.decide(automaton, [1, 3]) is None
+
+
+def test_fim_healing():
+    vocab = [b"def", b" fo", b"o(", b"x)", b" foo", b"foo(", b"oo("]
+    automaton = VocabPrefixAutomaton(vocab, build_suffix_index=True)
+    assert sorted(automaton.token_ids_ending_with(b"o(")) == [2, 5, 6]
+
+    decision = HealingPolicy().decide_fim(automaton, [0, 1], [2, 3])
+    assert decision.prefix.removed_bytes == b" fo"
+    assert decision.suffix.removed_bytes == b"o("
+    assert decision.suffix.num_removed_tokens == 1
+    assert decision.suffix.split_offset == 2
+    suffix_range = decision.suffix.suffix_rank_range
+    assert suffix_range.upper - suffix_range.lower == 3
+
+    automaton = VocabPrefixAutomaton(vocab)
+    assert automaton.token_ids_ending_with(b"o(") == []
+    assert HealingPolicy().decide_fim(automaton, [0, 1], [2, 3]).suffix is None
@@ -0,0 +1,62 @@
+from mtc_token_healing import PrefixCache
+
+
+def test_prefix_cache():
+    cache = PrefixCache()
+    a, _ = cache.insert([1, 2, 3, 4], "a")
+    b, _ = cache.insert([1, 2, 5], "b")
+    assert cache.num_tokens == 5
+    assert cache.insert([1, 2, 5], "c") == (b, "b")
+    try:
+        cache.insert([], "d")
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError")
+
+    upper = cache.parent(a)
+    assert cache.children(upper) == [a, b]
+    num_nodes = len(cache)
+    m = cache.match_prefix([1, 2, 3, 9])
+    assert (m.node, m.node_len, m.len, m.covering) == (None, 0, 3, a)
+    assert cache.handle(m.covering) == "a"
+    assert len(cache) == num_nodes
+    m = cache.match_prefix([1, 2, 5, 6])
+    assert (m.node, m.node_len, m.len) == (b, 3, 3)
+
+    cache.pin(a)
+    assert cache.ref_count(a) == 1
+    assert cache.evict(0) == ["c"]
+    assert b not in cache
+    cache.unpin(a)
+    try:
+        cache.unpin(a)
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError")
+    assert cache.evict(2) == ["a"]
+    assert cache.num_tokens == 2
+    assert len(cache) == 2
+
+
+def test_prefix_cache_stale_ids():
+    cache = PrefixCache()
+    n, _ = cache.insert([1], "x")
+    assert cache.evict(0) == ["x"]
+    m, _ = cache.insert([7, 8], "y")
+    assert m.index == n.index
+    assert m != n
+    assert n not in cache
+    assert cache.handle(n) is None
+    try:
+        cache.pin(n)
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError")
+
+
+if __name__ == "__main__":
+    test_prefix_cache()
+    test_prefix_cache_stale_ids()
@@ -1,4 +1,4 @@
-from mtc_token_healing import VocabPrefixAutomaton
+from mtc_token_healing import SortedTokenRange, VocabPrefixAutomaton
 
 
 def test_vocab_simple():
@@ -13,3 +13,96 @@ def test_vocab_simple():
     assert automaton.get_order() == order
 
     assert all(vocab[order[i]] < vocab[order[i + 1]] for i in range(len(order) - 1))
+
+
+def test_common_prefix():
+    vocab = [b"hello", b"help", b"hero", b"world", b"he"]
+    automaton = VocabPrefixAutomaton(vocab, build_lcp=True)
+
+    assert automaton.common_prefix(SortedTokenRange(0, 4)) == b"he"
+    assert automaton.common_prefix(SortedTokenRange(1, 3)) == b"hel"
+    assert automaton.common_prefix(SortedTokenRange(4, 5)) == b"world"
+    assert automaton.common_prefix(SortedTokenRange(0, 5)) == b""
+    assert automaton.common_prefix(SortedTokenRange(2, 2)) is None
+    assert VocabPrefixAutomaton(vocab).common_prefix(SortedTokenRange(0, 4)) is None
+
+
+def test_codec():
+    vocab = [b"he", b"llo", b"hello", b"", b"llo", b" world"]
+    automaton = VocabPrefixAutomaton(vocab)
+
+    assert automaton.token_ids_of(b"llo") == [1, 4]
+    assert automaton.token_ids_of(b"hello") == [2]
+    assert automaton.token_ids_of(b"hell") == []
+    assert automaton.token_ids_of(b"") == []
+
+    tokens = [0, 1, 3, 5]
+    assert automaton.decode(tokens) == b"hello world"
+    assert automaton.decode_with_offsets(tokens) == (b"hello world", [0, 2, 5, 5, 11])
+
+    try:
+        automaton.decode([0, len(vocab)])
+    except IndexError:
+        pass
+    else:
+        raise AssertionError("expected IndexError")
+
+
+def test_char_boundary_only():
+    vocab = ["歌".encode(), b"\x8c\xe8\xaf", "歌词".encode(), b"\xe8", b"a"]
+    automaton = VocabPrefixAutomaton(vocab)
+    tokens = [4, 0, 3]  # "a" + "歌" + "\xe8"
+
+    suffixes = [b for b, _ in automaton.parse_tokens(tokens)]
+    assert b"\x8c\xe8" in suffixes
+    bounded = [b for b, _ in automaton.parse_tokens(tokens, char_boundary_only=True)]
+    assert bounded == [b"\xe8", "歌".encode() + b"\xe8"]
+
+    assert automaton.parse_tokens_str_suffix(tokens) == []
+    lossless = automaton.parse_tokens_str_suffix_lossless(
+        tokens, char_boundary_only=True
+    )
+    assert [(s, p) for s, p, _ in lossless] == [("", b"\xe8"), ("歌", b"\xe8")]
+
+    text = b"a" + "歌".encode() + b"\xe8"
+    positions = [pos for pos, _ in automaton.parse_bytes(text, 0, True)]
+    assert positions == [4, 1]
+
+
+def test_utf8_classes():
+    vocab = [b"ab", "歌".encode()[:2], "歌".encode()[1:], "歌".encode()]
+    automaton = VocabPrefixAutomaton(vocab)
+
+    classes = automaton.get_utf8_classes()
+    assert len(classes) == len(vocab)
+    assert list(classes.starts_mid_char) == [False, False, True, False]
+    assert list(classes.ends_mid_char) == [False, True, False, False]
+    assert list(classes.num_pending_bytes) == [0, 1, 0, 0]
+    assert list(classes.is_ascii) == [True, False, False, False]
+
+    sorted_classes = automaton.get_utf8_classes(sorted=True)
+    order = automaton.get_order()
+    assert list(sorted_classes.is_ascii) == [classes.is_ascii[i] for i in order]
+
+
+def test_utf8_constraint():
+    vocab = [b"a", "歌".encode()[2:], "歌".encode()[1:], "歌".encode(), b"\x8c!", b"\xe6"]
+    automaton = VocabPrefixAutomaton(vocab)
+    constraint = automaton.utf8_constraint()
+    order = automaton.get_order()
+
+    def allowed(pending, within=None):
+        ranges = constraint.allowed(pending, within)
+        return sorted(order[k] for r in ranges for k in range(r.lower, r.upper))
+
+    assert allowed(b"") == [0, 3, 5]
+    assert allowed("歌".encode()[:1]) == [1, 2]
+    assert allowed("歌".encode()[:2]) == [1, 4]
+    assert allowed("歌".encode()[:2], SortedTokenRange(1, 2)) == [1]
+
+    try:
+        constraint.allowed(b"\x8c")
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError")
@@ -0,0 +1,123 @@
+use std::iter::FusedIterator;
+
+/// All token bytes concatenated into one buffer,
+/// with `offsets[i]..offsets[i + 1]` being the bytes of the `i`-th token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenArena {
+    bytes: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl Default for TokenArena {
+    fn default() -> Self {
+        Self {
+            bytes: Vec::new(),
+            offsets: vec![0],
+        }
+    }
+}
+
+impl TokenArena {
+    pub fn new<T: AsRef<[u8]>, V: IntoIterator<Item = T>>(vocab: V) -> Self {
+        let vocab = vocab.into_iter();
+        let mut res = Self::default();
+        res.offsets.reserve(vocab.size_hint().0);
+        vocab.for_each(|token| res.push(token));
+        res.bytes.shrink_to_fit();
+        res.offsets.shrink_to_fit();
+        res
+    }
+
+    pub fn push<T: AsRef<[u8]>>(&mut self, token: T) {
+        self.bytes.extend_from_slice(token.as_ref());
+        self.offsets.push(self.bytes.len());
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        let lower = *self.offsets.get(index)?;
+        let upper = *self.offsets.get(index + 1)?;
+        Some(&self.bytes[lower..upper])
+    }
+
+    pub fn iter(&self) -> TokenArenaIter<'_> {
+        TokenArenaIter {
+            arena: self,
+            lower: 0,
+            upper: self.len(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.bytes.capacity() + self.offsets.capacity() * size_of::<usize>()
+    }
+}
+
+impl<T: AsRef<[u8]>> FromIterator<T> for TokenArena {
+    fn from_iter<V: IntoIterator<Item = T>>(iter: V) -> Self {
+        Self::new(iter)
+    }
+}
+
+impl<'a> IntoIterator for &'a TokenArena {
+    type Item = &'a [u8];
+    type IntoIter = TokenArenaIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenArenaIter<'a> {
+    arena: &'a TokenArena,
+    lower: usize,
+    upper: usize,
+}
+
+impl<'a> Iterator for TokenArenaIter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.lower >= self.upper {
+            return None;
+        }
+        let res = self.arena.get(self.lower);
+        self.lower += 1;
+        res
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.upper - self.lower;
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for TokenArenaIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.lower >= self.upper {
+            return None;
+        }
+        self.upper -= 1;
+        self.arena.get(self.upper)
+    }
+}
+
+impl ExactSizeIterator for TokenArenaIter<'_> {}
+
+impl FusedIterator for TokenArenaIter<'_> {}
@@ -1,45 +1,103 @@
-use general_sam::{BoxBisectTable, GeneralSam};
+use general_sam::{
+    BoxBisectTable, GeneralSam, GeneralSamNode, GeneralSamNodeID, SAM_NIL_NODE_ID,
+    SAM_ROOT_NODE_ID, TransitionTable,
+};
 
+use crate::lcp::LcpIndex;
+use crate::suffix::SuffixIndex;
 use crate::token::{
-    build_sam_of_reversed_tokens, label_rank_range_on_sam_of_rev_tokens, sort_vocab_with_trie,
+    build_sam_of_reversed_tokens, join, label_rank_range_on_sam_of_rev_tokens, sort_vocab,
+};
+use crate::transition::DenseTransitions;
+use crate::{
+    ForwardPrefixCursor, PrefixCursor, PrefixIndex, SmallToken, SortedTokenId, SortedTokenRange,
+    TokenArena, TokenId, TokenSuffixIndex, Utf8Class, Utf8Constraint, VocabTrie,
 };
-use crate::{SmallToken, SortedTokenId, SortedTokenRange, TokenId};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AutomatonOptions {
+    /// States with at least this many transitions get a dense 256-entry row,
+    /// any value above 256 disables dense rows.
+    pub dense_threshold: usize,
+    /// Whether to build the LCP array for common prefix queries.
+    pub build_lcp: bool,
+    /// Whether to keep tokens sorted by reversed bytes for suffix queries.
+    pub build_suffix_index: bool,
+}
+
+impl Default for AutomatonOptions {
+    fn default() -> Self {
+        Self {
+            dense_threshold: 32,
+            build_lcp: false,
+            build_suffix_index: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 #[cfg_attr(feature = "pyo3", ::pyo3::pyclass(frozen))]
 pub struct VocabPrefixAutomaton {
-    vocab: Vec<SmallToken>,
+    vocab: TokenArena,
     order: Vec<TokenId>,
     rank: Vec<SortedTokenId>,
     sam_of_rev_tokens: GeneralSam<BoxBisectTable<u8>>,
+    dense_trans: DenseTransitions,
     rank_range_on_sam: Vec<Option<SortedTokenRange>>,
+    lcp: Option<LcpIndex>,
+    suffix_index: Option<SuffixIndex>,
+    utf8_classes: Vec<Utf8Class>,
+    sorted_utf8_classes: Vec<Utf8Class>,
 }
 
 impl VocabPrefixAutomaton {
     pub fn new<T: AsRef<[u8]>, V: IntoIterator<Item = T>>(vocab: V) -> Self {
-        let vocab: Vec<_> = vocab
-            .into_iter()
-            .map(|token| SmallToken::from(token.as_ref()))
-            .collect();
-        let sort_result = sort_vocab_with_trie(vocab.iter().map(|x| x.as_slice()));
-        let sam_of_rev_tokens = build_sam_of_reversed_tokens(vocab.iter().map(|x| x.as_slice()));
+        Self::with_options(vocab, &AutomatonOptions::default())
+    }
+
+    pub fn with_options<T: AsRef<[u8]>, V: IntoIterator<Item = T>>(
+        vocab: V,
+        options: &AutomatonOptions,
+    ) -> Self {
+        let vocab = TokenArena::new(vocab);
+        let (sort_result, (sam_of_rev_tokens, rev_order)) = join(
+            || sort_vocab(&vocab),
+            || build_sam_of_reversed_tokens(&vocab),
+        );
         let cnt_info_of_sam_rev = label_rank_range_on_sam_of_rev_tokens(
             &sam_of_rev_tokens,
-            vocab
-                .iter()
-                .map(|x| x.as_slice())
-                .zip(sort_result.rank_ranges),
+            &vocab,
+            &rev_order,
+            &sort_result.rank_ranges,
         );
+        let dense_trans = DenseTransitions::new(&sam_of_rev_tokens, options.dense_threshold);
+        let lcp = options
+            .build_lcp
+            .then(|| LcpIndex::new(&vocab, &sort_result.order));
+        let suffix_index = options
+            .build_suffix_index
+            .then(|| SuffixIndex::new(rev_order));
+        let utf8_classes: Vec<_> = vocab.iter().map(Utf8Class::of).collect();
+        let sorted_utf8_classes = sort_result
+            .order
+            .iter()
+            .map(|&id| utf8_classes[id as usize])
+            .collect();
         Self {
             vocab,
             order: sort_result.order,
             rank: sort_result.rank,
             sam_of_rev_tokens,
+            dense_trans,
             rank_range_on_sam: cnt_info_of_sam_rev,
+            lcp,
+            suffix_index,
+            utf8_classes,
+            sorted_utf8_classes,
         }
     }
 
-    pub fn vocab(&self) -> &[SmallToken] {
+    pub fn vocab(&self) -> &TokenArena {
         &self.vocab
     }
 
@@ -51,79 +109,215 @@ impl VocabPrefixAutomaton {
         &self.rank
     }
 
-    pub fn get(&self, index: usize) -> Option<&SmallToken> {
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
         self.vocab.get(index).filter(|t| !t.is_empty())
     }
 
+    /// Estimated number of bytes allocated on the heap by this automaton.
+    pub fn heap_size(&self) -> usize {
+        self.vocab.heap_size()
+            + self.order.capacity() * size_of::<TokenId>()
+            + self.rank.capacity() * size_of::<SortedTokenId>()
+            + sam_heap_size(&self.sam_of_rev_tokens)
+            + self.dense_trans.heap_size()
+            + self.rank_range_on_sam.capacity() * size_of::<Option<SortedTokenRange>>()
+            + self.lcp.as_ref().map_or(0, LcpIndex::heap_size)
+            + self.suffix_index.as_ref().map_or(0, SuffixIndex::heap_size)
+            + (self.utf8_classes.capacity() + self.sorted_utf8_classes.capacity())
+                * size_of::<Utf8Class>()
+    }
+
+    /// Original ids of tokens sorted by their reversed bytes,
+    /// `None` if the suffix index is not built.
+    pub fn suffix_order(&self) -> Option<&[TokenId]> {
+        self.suffix_index.as_ref().map(SuffixIndex::order)
+    }
+
+    /// The range in [`VocabPrefixAutomaton::suffix_order`] of tokens ending with `bytes`.
+    pub fn tokens_ending_with<B: AsRef<[u8]>>(&self, bytes: B) -> Option<SortedTokenRange> {
+        self.suffix_index
+            .as_ref()?
+            .range_ending_with(&self.vocab, bytes.as_ref())
+    }
+
+    /// Original ids of tokens ending with `bytes`.
+    pub fn token_ids_ending_with<B: AsRef<[u8]>>(&self, bytes: B) -> &[TokenId] {
+        match (self.suffix_order(), self.tokens_ending_with(bytes)) {
+            (Some(order), Some(range)) => &order[range.lower as usize..range.upper as usize],
+            _ => &[],
+        }
+    }
+
+    /// [`Utf8Class`] of tokens indexed by original ids.
+    pub fn utf8_classes(&self) -> &[Utf8Class] {
+        &self.utf8_classes
+    }
+
+    /// [`Utf8Class`] of tokens indexed by sorted ids.
+    pub fn sorted_utf8_classes(&self) -> &[Utf8Class] {
+        &self.sorted_utf8_classes
+    }
+
+    /// Number of states stepped through dense rows instead of bisect tables.
+    pub fn num_of_dense_states(&self) -> usize {
+        self.dense_trans.num_of_rows()
+    }
+
+    /// Length of the longest common prefix of all tokens in `range`,
+    /// `None` if the range is empty or the LCP array is not built.
+    pub fn common_prefix_len(&self, range: &SortedTokenRange) -> Option<usize> {
+        self.lcp
+            .as_ref()?
+            .common_prefix_len(&self.vocab, &self.order, range)
+    }
+
+    /// The longest common prefix of all tokens in `range`.
+    pub fn common_prefix(&self, range: &SortedTokenRange) -> Option<&[u8]> {
+        let len = self.common_prefix_len(range)?;
+        let token = self.vocab.get(self.order[range.lower as usize] as usize)?;
+        Some(&token[..len])
+    }
+
+    #[inline]
+    fn goto(&self, state: GeneralSamNodeID, byte: u8) -> GeneralSamNodeID {
+        if let Some(row) = self.dense_trans.row(state) {
+            return row[byte as usize] as GeneralSamNodeID;
+        }
+        self.sam_of_rev_tokens
+            .get_node(state)
+            .and_then(|node| node.get_trans().get(&byte))
+            .copied()
+            .unwrap_or(SAM_NIL_NODE_ID)
+    }
+
+    pub fn cursor(&self) -> PrefixCursor<'_> {
+        PrefixCursor::new(self)
+    }
+
+    pub fn forward_cursor(&self) -> ForwardPrefixCursor<'_> {
+        ForwardPrefixCursor::new(self)
+    }
+
+    /// Builds the prefix tree of the vocab.
+    pub fn vocab_trie(&self) -> VocabTrie {
+        VocabTrie::new(self)
+    }
+
+    /// Builds the constraint keeping generated bytes valid UTF-8.
+    pub fn utf8_constraint(&self) -> Utf8Constraint {
+        Utf8Constraint::new(self)
+    }
+
+    pub fn token_ids_of<B: AsRef<[u8]>>(&self, bytes: B) -> &[TokenId] {
+        PrefixIndex::token_ids_of(self, bytes)
+    }
+
+    pub fn decode<S: IntoIterator<Item = usize>>(&self, token_ids: S) -> Option<Vec<u8>> {
+        PrefixIndex::decode(self, token_ids)
+    }
+
+    pub fn decode_with_offsets<S: IntoIterator<Item = usize>>(
+        &self,
+        token_ids: S,
+    ) -> Option<(Vec<u8>, Vec<usize>)> {
+        PrefixIndex::decode_with_offsets(self, token_ids)
+    }
+
     pub fn parse_bytes<B: AsRef<[u8]>>(
         &self,
         bytes: B,
         start_from: usize,
     ) -> Vec<(usize, SortedTokenRange)> {
-        let bytes = bytes.as_ref();
-
-        let mut state = self.sam_of_rev_tokens.get_root_state();
-        let mut res = Vec::new();
+        PrefixIndex::parse_bytes(self, bytes, start_from)
+    }
 
-        for (pos, byte) in bytes
-            .iter()
-            .enumerate()
-            .rev()
-            .take_while(|(pos, _)| *pos >= start_from)
-        {
-            state.goto(byte);
-            if state.is_nil() {
-                break;
-            }
-            if let Some(cnt_info) = self.rank_range_on_sam[state.node_id].clone() {
-                res.push((pos, cnt_info));
-            }
-        }
+    pub fn parse_rev_token_id_seq<S: IntoIterator<Item = usize>>(
+        &self,
+        rev_tokens: S,
+    ) -> Vec<(SmallToken, SortedTokenRange)> {
+        PrefixIndex::parse_rev_token_id_seq(self, rev_tokens)
+    }
 
-        res
+    pub fn parse_bytes_at_char_boundaries<B: AsRef<[u8]>>(
+        &self,
+        bytes: B,
+        start_from: usize,
+    ) -> Vec<(usize, SortedTokenRange)> {
+        PrefixIndex::parse_bytes_at_char_boundaries(self, bytes, start_from)
     }
 
-    pub fn parse_rev_token_id_seq<S: IntoIterator<Item = usize>>(
+    pub fn parse_rev_token_id_seq_at_char_boundaries<S: IntoIterator<Item = usize>>(
         &self,
         rev_tokens: S,
     ) -> Vec<(SmallToken, SortedTokenRange)> {
-        let mut state = self.sam_of_rev_tokens.get_root_state();
-        let mut res = Vec::new();
-        let mut bytes_rev = Vec::new();
+        PrefixIndex::parse_rev_token_id_seq_at_char_boundaries(self, rev_tokens)
+    }
+}
 
-        for id in rev_tokens {
-            if state.is_nil() {
-                break;
-            }
-            let Some(token) = self.get(id) else {
-                break;
-            };
-            for byte in token.iter().rev() {
-                state.goto(byte);
-                bytes_rev.push(*byte);
-                if state.is_nil() {
-                    break;
-                }
-                if let Some(cnt_info) = self.rank_range_on_sam[state.node_id].clone() {
-                    let mut bytes = bytes_rev.clone();
-                    bytes.reverse();
-                    res.push((SmallToken::from(bytes.as_slice()), cnt_info));
-                }
-            }
-        }
+impl TokenSuffixIndex for VocabPrefixAutomaton {
+    fn suffix_order(&self) -> Option<&[TokenId]> {
+        VocabPrefixAutomaton::suffix_order(self)
+    }
+
+    fn tokens_ending_with(&self, bytes: &[u8]) -> Option<SortedTokenRange> {
+        VocabPrefixAutomaton::tokens_ending_with(self, bytes)
+    }
+}
+
+impl PrefixIndex for VocabPrefixAutomaton {
+    type State = GeneralSamNodeID;
+
+    fn vocab(&self) -> &TokenArena {
+        &self.vocab
+    }
+
+    fn order(&self) -> &[TokenId] {
+        &self.order
+    }
+
+    fn rank(&self) -> &[SortedTokenId] {
+        &self.rank
+    }
 
-        res
+    fn root_state(&self) -> Self::State {
+        SAM_ROOT_NODE_ID
     }
+
+    fn feed_rev(&self, state: &mut Self::State, byte: u8) {
+        *state = self.goto(*state, byte);
+    }
+
+    fn is_nil(&self, state: &Self::State) -> bool {
+        *state == SAM_NIL_NODE_ID
+    }
+
+    fn rank_range(&self, state: &Self::State) -> Option<SortedTokenRange> {
+        self.rank_range_on_sam.get(*state).cloned().flatten()
+    }
+}
+
+fn sam_heap_size(sam: &GeneralSam<BoxBisectTable<u8>>) -> usize {
+    let num_of_trans: usize = (0..sam.num_of_nodes())
+        .filter_map(|id| sam.get_node(id))
+        .map(|node| node.get_trans().iter().count())
+        .sum();
+    sam.num_of_nodes() * size_of::<GeneralSamNode<BoxBisectTable<u8>>>()
+        + num_of_trans * size_of::<(u8, GeneralSamNodeID)>()
+        + sam.get_topo_and_suf_len_sorted_node_ids().capacity() * size_of::<GeneralSamNodeID>()
 }
 
 #[cfg(feature = "pyo3")]
 pub mod pyo3 {
+    use pyo3::exceptions::PyIndexError;
     use pyo3::types::PyBytes;
-    use pyo3::{Bound, FromPyObject, IntoPyObject, Python, pymethods};
+    use pyo3::{Bound, FromPyObject, IntoPyObject, PyResult, Python, pymethods};
 
-    use crate::{SortedTokenId, SortedTokenRange, TokenId};
+    use crate::{
+        SmallToken, SortedTokenId, SortedTokenRange, TokenId, Utf8ClassColumns, Utf8Constraint,
+        split_utf8,
+    };
 
-    use super::VocabPrefixAutomaton;
+    use super::{AutomatonOptions, VocabPrefixAutomaton};
 
     #[derive(Debug, FromPyObject, IntoPyObject)]
     enum TokenIdSeq {
@@ -145,11 +339,41 @@ pub mod pyo3 {
         }
     }
 
+    impl VocabPrefixAutomaton {
+        fn parse_tokens_with(
+            &self,
+            tokens: Vec<usize>,
+            char_boundary_only: bool,
+        ) -> Vec<(SmallToken, SortedTokenRange)> {
+            let rev_tokens = tokens.into_iter().rev();
+            if char_boundary_only {
+                self.parse_rev_token_id_seq_at_char_boundaries(rev_tokens)
+            } else {
+                self.parse_rev_token_id_seq(rev_tokens)
+            }
+        }
+    }
+
     #[pymethods]
     impl VocabPrefixAutomaton {
         #[new]
-        fn py_new(vocab: Vec<Vec<u8>>) -> Self {
-            Self::new(vocab)
+        #[pyo3(signature=(vocab, dense_threshold=None, build_lcp=false, build_suffix_index=false))]
+        fn py_new(
+            py: Python<'_>,
+            vocab: Vec<Vec<u8>>,
+            dense_threshold: Option<usize>,
+            build_lcp: bool,
+            build_suffix_index: bool,
+        ) -> Self {
+            let mut options = AutomatonOptions {
+                build_lcp,
+                build_suffix_index,
+                ..Default::default()
+            };
+            if let Some(dense_threshold) = dense_threshold {
+                options.dense_threshold = dense_threshold;
+            }
+            py.detach(|| Self::with_options(vocab, &options))
         }
 
         #[getter("vocab_size")]
@@ -157,6 +381,37 @@ pub mod pyo3 {
             self.vocab.len()
         }
 
+        #[pyo3(name = "heap_size")]
+        fn heap_size_py(&self) -> usize {
+            self.heap_size()
+        }
+
+        #[pyo3(name = "common_prefix")]
+        fn common_prefix_py<'py>(
+            &self,
+            py: Python<'py>,
+            rank_range: &SortedTokenRange,
+        ) -> Option<Bound<'py, PyBytes>> {
+            self.common_prefix(rank_range)
+                .map(|prefix| PyBytes::new(py, prefix))
+        }
+
+        #[pyo3(name = "get_utf8_classes")]
+        #[pyo3(signature=(sorted=false))]
+        fn get_utf8_classes_py(&self, py: Python<'_>, sorted: bool) -> Utf8ClassColumns {
+            let classes = if sorted {
+                &self.sorted_utf8_classes
+            } else {
+                &self.utf8_classes
+            };
+            py.detach(|| classes.iter().copied().collect())
+        }
+
+        #[pyo3(name = "utf8_constraint")]
+        fn utf8_constraint_py(&self, py: Python<'_>) -> Utf8Constraint {
+            py.detach(|| self.utf8_constraint())
+        }
+
         #[pyo3(name = "get_order")]
         fn get_order_py(&self) -> &[TokenId] {
             &self.order
@@ -167,42 +422,105 @@ pub mod pyo3 {
             &self.rank
         }
 
+        #[pyo3(name = "token_ids_of")]
+        fn token_ids_of_py(&self, bytes: &[u8]) -> Vec<TokenId> {
+            self.token_ids_of(bytes).to_vec()
+        }
+
+        #[pyo3(name = "token_ids_ending_with")]
+        fn token_ids_ending_with_py(&self, bytes: &[u8]) -> Vec<TokenId> {
+            self.token_ids_ending_with(bytes).to_vec()
+        }
+
+        #[pyo3(name = "decode")]
+        fn decode_py<'py>(
+            &self,
+            py: Python<'py>,
+            tokens: Vec<usize>,
+        ) -> PyResult<Bound<'py, PyBytes>> {
+            let res = py.detach(|| self.decode(tokens));
+            res.map(|bytes| PyBytes::new(py, &bytes))
+                .ok_or_else(|| PyIndexError::new_err("token id out of range"))
+        }
+
+        #[pyo3(name = "decode_with_offsets")]
+        fn decode_with_offsets_py<'py>(
+            &self,
+            py: Python<'py>,
+            tokens: Vec<usize>,
+        ) -> PyResult<(Bound<'py, PyBytes>, Vec<usize>)> {
+            let res = py.detach(|| self.decode_with_offsets(tokens));
+            res.map(|(bytes, offsets)| (PyBytes::new(py, &bytes), offsets))
+                .ok_or_else(|| PyIndexError::new_err("token id out of range"))
+        }
+
         #[pyo3(name = "parse_bytes")]
+        #[pyo3(signature=(bytes, start_from, char_boundary_only=false))]
         fn parse_bytes_py(
             &self,
             py: Python<'_>,
             bytes: &[u8],
             start_from: usize,
+            char_boundary_only: bool,
         ) -> Vec<(usize, SortedTokenRange)> {
-            py.detach(|| self.parse_bytes(bytes, start_from))
+            py.detach(|| {
+                if char_boundary_only {
+                    self.parse_bytes_at_char_boundaries(bytes, start_from)
+                } else {
+                    self.parse_bytes(bytes, start_from)
+                }
+            })
         }
 
         #[pyo3(name = "parse_tokens")]
+        #[pyo3(signature=(tokens, char_boundary_only=false))]
         fn parse_tokens_py<'py>(
             &self,
             py: Python<'py>,
             tokens: Vec<usize>,
+            char_boundary_only: bool,
         ) -> Vec<(Bound<'py, PyBytes>, SortedTokenRange)> {
-            let res = py.detach(|| self.parse_rev_token_id_seq(tokens.into_iter().rev()));
+            let res = py.detach(|| self.parse_tokens_with(tokens, char_boundary_only));
             res.into_iter()
                 .map(|(b, c)| (PyBytes::new(py, &b), c))
                 .collect()
         }
 
         #[pyo3(name = "parse_tokens_str_suffix")]
+        #[pyo3(signature=(tokens, char_boundary_only=false))]
         fn parse_tokens_str_suffix_py(
             &self,
             py: Python<'_>,
             tokens: Vec<usize>,
+            char_boundary_only: bool,
         ) -> Vec<(String, SortedTokenRange)> {
             py.detach(|| {
-                self.parse_rev_token_id_seq(tokens.into_iter().rev())
+                self.parse_tokens_with(tokens, char_boundary_only)
                     .into_iter()
                     .filter_map(|(b, c)| String::from_utf8(b.into()).ok().map(|s| (s, c)))
                     .collect()
             })
         }
 
+        /// Like `parse_tokens_str_suffix`, but splitting each suffix into
+        /// its valid UTF-8 prefix and the pending bytes instead of dropping it.
+        #[pyo3(name = "parse_tokens_str_suffix_lossless")]
+        #[pyo3(signature=(tokens, char_boundary_only=false))]
+        fn parse_tokens_str_suffix_lossless_py<'py>(
+            &self,
+            py: Python<'py>,
+            tokens: Vec<usize>,
+            char_boundary_only: bool,
+        ) -> Vec<(String, Bound<'py, PyBytes>, SortedTokenRange)> {
+            let res = py.detach(|| self.parse_tokens_with(tokens, char_boundary_only));
+            res.into_iter()
+                .map(|(b, c)| {
+                    let (valid, pending) = split_utf8(&b);
+                    (valid.to_owned(), PyBytes::new(py, pending), c)
+                })
+                .collect()
+        }
+
         fn get_original_token_ids(&self, py: Python<'_>, seq: TokenIdSeq) -> TokenIdSeq {
             py.detach(|| seq.map(|id| self.order.get(id as usize).copied().unwrap_or(id)))
         }
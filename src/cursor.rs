@@ -0,0 +1,240 @@
+use std::fmt;
+
+use crate::{PrefixIndex, SortedTokenId, SortedTokenRange, TokenId, VocabPrefixAutomaton};
+
+/// Finds tokens prefixed with a string fed byte by byte from its end,
+/// which can be paused, cloned or checkpointed between bytes.
+pub struct PrefixCursor<'a, P: PrefixIndex = VocabPrefixAutomaton> {
+    index: &'a P,
+    state: P::State,
+    num_bytes: usize,
+}
+
+/// A [`PrefixCursor`] detached from its index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefixCursorCheckpoint<S> {
+    pub state: S,
+    pub num_bytes: usize,
+}
+
+impl<'a, P: PrefixIndex> PrefixCursor<'a, P> {
+    pub fn new(index: &'a P) -> Self {
+        Self {
+            index,
+            state: index.root_state(),
+            num_bytes: 0,
+        }
+    }
+
+    pub fn from_checkpoint(index: &'a P, checkpoint: PrefixCursorCheckpoint<P::State>) -> Self {
+        Self {
+            index,
+            state: checkpoint.state,
+            num_bytes: checkpoint.num_bytes,
+        }
+    }
+
+    pub fn index(&self) -> &'a P {
+        self.index
+    }
+
+    pub fn state(&self) -> &P::State {
+        &self.state
+    }
+
+    /// Number of bytes fed so far.
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.index.is_nil(&self.state)
+    }
+
+    /// The range of sorted tokens prefixed with the bytes fed so far.
+    pub fn rank_range(&self) -> Option<SortedTokenRange> {
+        self.index.rank_range(&self.state)
+    }
+
+    /// Prepends `byte` to the bytes fed so far.
+    pub fn feed(&mut self, byte: u8) -> Option<SortedTokenRange> {
+        self.index.feed_rev(&mut self.state, byte);
+        self.num_bytes += 1;
+        self.rank_range()
+    }
+
+    /// Prepends all bytes of a token,
+    /// stopping early if the cursor becomes nil.
+    ///
+    /// Returns `false` for unknown or empty tokens, leaving the cursor
+    /// unchanged.
+    pub fn feed_token(&mut self, token_id: usize) -> bool {
+        let Some(token) = self.index.get(token_id) else {
+            return false;
+        };
+        for &byte in token.iter().rev() {
+            if self.is_nil() {
+                break;
+            }
+            self.feed(byte);
+        }
+        true
+    }
+
+    pub fn checkpoint(&self) -> PrefixCursorCheckpoint<P::State> {
+        PrefixCursorCheckpoint {
+            state: self.state.clone(),
+            num_bytes: self.num_bytes,
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: PrefixCursorCheckpoint<P::State>) {
+        self.state = checkpoint.state;
+        self.num_bytes = checkpoint.num_bytes;
+    }
+
+    pub fn reset(&mut self) {
+        self.state = self.index.root_state();
+        self.num_bytes = 0;
+    }
+}
+
+impl<P: PrefixIndex> Clone for PrefixCursor<'_, P> {
+    fn clone(&self) -> Self {
+        Self {
+            index: self.index,
+            state: self.state.clone(),
+            num_bytes: self.num_bytes,
+        }
+    }
+}
+
+impl<P: PrefixIndex> fmt::Debug for PrefixCursor<'_, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefixCursor")
+            .field("state", &self.state)
+            .field("num_bytes", &self.num_bytes)
+            .finish()
+    }
+}
+
+/// Narrows down tokens prefixed with a string fed byte by byte from its start,
+/// by binary searching the sorted vocab within the current range.
+pub struct ForwardPrefixCursor<'a, P: PrefixIndex = VocabPrefixAutomaton> {
+    index: &'a P,
+    rank_range: SortedTokenRange,
+    num_bytes: usize,
+}
+
+impl<'a, P: PrefixIndex> ForwardPrefixCursor<'a, P> {
+    pub fn new(index: &'a P) -> Self {
+        Self {
+            index,
+            rank_range: SortedTokenRange {
+                lower: 0,
+                upper: index.order().len() as SortedTokenId,
+            },
+            num_bytes: 0,
+        }
+    }
+
+    pub fn index(&self) -> &'a P {
+        self.index
+    }
+
+    /// Number of bytes fed so far.
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.rank_range.lower >= self.rank_range.upper
+    }
+
+    /// The range of sorted tokens prefixed with the bytes fed so far.
+    pub fn rank_range(&self) -> Option<SortedTokenRange> {
+        (!self.is_nil()).then(|| self.rank_range.clone())
+    }
+
+    /// The range of sorted tokens equal to the bytes fed so far.
+    pub fn exact_rank_range(&self) -> Option<SortedTokenRange> {
+        let upper = self.rank_range.lower + self.num_of_exact_tokens() as SortedTokenId;
+        (upper > self.rank_range.lower).then_some(SortedTokenRange {
+            lower: self.rank_range.lower,
+            upper,
+        })
+    }
+
+    /// Original ids of tokens equal to the bytes fed so far.
+    pub fn exact_token_ids(&self) -> &'a [TokenId] {
+        let lower = self.rank_range.lower as usize;
+        &self.index.order()[lower..lower + self.num_of_exact_tokens()]
+    }
+
+    /// Appends `byte` to the bytes fed so far.
+    pub fn feed(&mut self, byte: u8) -> Option<SortedTokenRange> {
+        let ids = &self.sorted_ids()[self.num_of_exact_tokens()..];
+        let lower = ids.partition_point(|&id| self.byte_at(id) < byte);
+        let upper = lower + ids[lower..].partition_point(|&id| self.byte_at(id) == byte);
+        let offset = self.rank_range.upper as usize - ids.len();
+        self.rank_range = SortedTokenRange {
+            lower: (offset + lower) as SortedTokenId,
+            upper: (offset + upper) as SortedTokenId,
+        };
+        self.num_bytes += 1;
+        self.rank_range()
+    }
+
+    pub fn feed_bytes<B: AsRef<[u8]>>(&mut self, bytes: B) -> Option<SortedTokenRange> {
+        for &byte in bytes.as_ref() {
+            if self.is_nil() {
+                break;
+            }
+            self.feed(byte);
+        }
+        self.rank_range()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.index);
+    }
+
+    fn sorted_ids(&self) -> &'a [TokenId] {
+        let SortedTokenRange { lower, upper } = self.rank_range;
+        self.index
+            .order()
+            .get(lower as usize..upper as usize)
+            .unwrap_or_default()
+    }
+
+    fn byte_at(&self, id: TokenId) -> u8 {
+        // only called on tokens longer than the bytes fed so far
+        self.index.vocab().get(id as usize).unwrap_or_default()[self.num_bytes]
+    }
+
+    fn num_of_exact_tokens(&self) -> usize {
+        let vocab = self.index.vocab();
+        self.sorted_ids().partition_point(|&id| {
+            vocab.get(id as usize).unwrap_or_default().len() <= self.num_bytes
+        })
+    }
+}
+
+impl<P: PrefixIndex> Clone for ForwardPrefixCursor<'_, P> {
+    fn clone(&self) -> Self {
+        Self {
+            index: self.index,
+            rank_range: self.rank_range.clone(),
+            num_bytes: self.num_bytes,
+        }
+    }
+}
+
+impl<P: PrefixIndex> fmt::Debug for ForwardPrefixCursor<'_, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForwardPrefixCursor")
+            .field("rank_range", &self.rank_range)
+            .field("num_bytes", &self.num_bytes)
+            .finish()
+    }
+}
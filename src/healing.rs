@@ -0,0 +1,342 @@
+use std::collections::BTreeSet;
+
+use crate::{PrefixIndex, SmallToken, SortedTokenRange, TokenId, TokenSuffixIndex};
+
+/// Which match to heal with when several are allowed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(eq, eq_int, from_py_object))]
+pub enum HealingPreference {
+    /// The longest removed suffix.
+    #[default]
+    Deepest,
+    /// The shortest removed suffix.
+    Shallowest,
+}
+
+/// Rules turning the suffixes found by
+/// [`PrefixIndex::parse_rev_token_id_seq`] into a single healing decision.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, set_all, skip_from_py_object))]
+pub struct HealingPolicy {
+    /// Number of trailing tokens the removed suffix may touch.
+    pub max_tokens: usize,
+    pub max_bytes: usize,
+    /// Whether the removed suffix may contain spaces or tabs after its first byte.
+    pub heal_through_whitespace: bool,
+    /// Whether the removed suffix may contain line breaks after its first byte.
+    pub heal_through_newline: bool,
+    /// Skips matches prefixing more tokens than this.
+    pub max_candidates: usize,
+    pub prefer: HealingPreference,
+    /// Tokens never removed nor healed across, such as role markers.
+    pub barrier_token_ids: BTreeSet<TokenId>,
+}
+
+/// Where to cut the token sequence and what to generate next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct HealingDecision {
+    /// Number of trailing tokens touched by the removed suffix.
+    pub num_removed_tokens: usize,
+    /// Offset of the removed suffix in the first removed token.
+    pub split_offset: usize,
+    pub removed_bytes: Vec<u8>,
+    /// Sorted tokens prefixed with the removed bytes.
+    pub rank_range: SortedTokenRange,
+}
+
+/// Where to cut the start of a suffix segment and what generation should end with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct FimSuffixDecision {
+    /// Number of leading tokens touched by the removed prefix.
+    pub num_removed_tokens: usize,
+    /// Offset where the removed prefix ends in the last removed token.
+    pub split_offset: usize,
+    pub removed_bytes: Vec<u8>,
+    /// Tokens ending with the removed bytes,
+    /// as a range of [`TokenSuffixIndex::suffix_order`].
+    pub suffix_rank_range: SortedTokenRange,
+}
+
+/// Healing both sides of the gap in a fill-in-the-middle prompt.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct FimHealingDecision {
+    /// Generation starts with a token prefixed with these removed bytes.
+    pub prefix: Option<HealingDecision>,
+    /// Generation ends with a token ending with these removed bytes.
+    pub suffix: Option<FimSuffixDecision>,
+}
+
+impl Default for HealingPolicy {
+    fn default() -> Self {
+        Self::permissive()
+    }
+}
+
+impl HealingPolicy {
+    /// Heals with the longest match found, whatever it contains.
+    pub fn permissive() -> Self {
+        Self {
+            max_tokens: usize::MAX,
+            max_bytes: usize::MAX,
+            heal_through_whitespace: true,
+            heal_through_newline: true,
+            max_candidates: usize::MAX,
+            prefer: HealingPreference::Deepest,
+            barrier_token_ids: BTreeSet::new(),
+        }
+    }
+
+    /// Heals within the last token, never across words or lines,
+    /// and skips matches too vague to constrain generation.
+    pub fn conservative() -> Self {
+        Self {
+            max_tokens: 1,
+            max_bytes: 16,
+            heal_through_whitespace: false,
+            heal_through_newline: false,
+            max_candidates: 1024,
+            prefer: HealingPreference::Deepest,
+            barrier_token_ids: BTreeSet::new(),
+        }
+    }
+
+    /// Heals the last few tokens within a line.
+    pub fn line_local() -> Self {
+        Self {
+            max_tokens: 4,
+            max_bytes: 64,
+            heal_through_newline: false,
+            ..Self::permissive()
+        }
+    }
+
+    fn is_barrier_token(&self, id: usize) -> bool {
+        TokenId::try_from(id).is_ok_and(|id| self.barrier_token_ids.contains(&id))
+    }
+
+    fn is_barrier(&self, byte: u8) -> bool {
+        match byte {
+            b'\n' | b'\r' => !self.heal_through_newline,
+            b' ' | b'\t' | b'\x0b' | b'\x0c' => !self.heal_through_whitespace,
+            _ => false,
+        }
+    }
+
+    /// Decides how to heal the end of `tokens`, `None` if no match is allowed.
+    pub fn decide<P: PrefixIndex>(&self, index: &P, tokens: &[usize]) -> Option<HealingDecision> {
+        self.decide_from(index, tokens, 0)
+    }
+
+    /// Like [`HealingPolicy::decide`], never touching `tokens[..min_index]`.
+    pub fn decide_from<P: PrefixIndex>(
+        &self,
+        index: &P,
+        tokens: &[usize],
+        min_index: usize,
+    ) -> Option<HealingDecision> {
+        let after_barrier = tokens
+            .iter()
+            .rposition(|&id| self.is_barrier_token(id))
+            .map_or(0, |i| i + 1);
+        let start = min_index
+            .max(after_barrier)
+            .max(tokens.len().saturating_sub(self.max_tokens))
+            .min(tokens.len());
+        let tokens = &tokens[start..];
+        let candidates = index
+            .parse_rev_token_id_seq(tokens.iter().rev().copied())
+            .into_iter()
+            .take_while(|(bytes, _)| {
+                // longer suffixes contain the barriers of shorter ones
+                bytes.len() <= self.max_bytes && !bytes[1..].iter().any(|&b| self.is_barrier(b))
+            })
+            .filter(|(_, range)| (range.upper - range.lower) as usize <= self.max_candidates);
+        let (removed_bytes, rank_range) = match self.prefer {
+            HealingPreference::Deepest => candidates.last(),
+            HealingPreference::Shallowest => candidates.into_iter().next(),
+        }?;
+        Some(locate(index, tokens, removed_bytes, rank_range))
+    }
+
+    /// Heals the end of `prefix_tokens` and the start of `suffix_tokens`.
+    ///
+    /// The suffix side needs the suffix index,
+    /// see [`AutomatonOptions::build_suffix_index`](crate::AutomatonOptions).
+    pub fn decide_fim<P: PrefixIndex + TokenSuffixIndex>(
+        &self,
+        index: &P,
+        prefix_tokens: &[usize],
+        suffix_tokens: &[usize],
+    ) -> FimHealingDecision {
+        FimHealingDecision {
+            prefix: self.decide(index, prefix_tokens),
+            suffix: self.decide_suffix(index, suffix_tokens),
+        }
+    }
+
+    fn decide_suffix<P: PrefixIndex + TokenSuffixIndex>(
+        &self,
+        index: &P,
+        tokens: &[usize],
+    ) -> Option<FimSuffixDecision> {
+        let end = tokens
+            .iter()
+            .position(|&id| self.is_barrier_token(id))
+            .unwrap_or(tokens.len())
+            .min(self.max_tokens);
+        let mut bytes = Vec::new();
+        for &id in &tokens[..end] {
+            let Some(token) = index.get(id) else {
+                break;
+            };
+            bytes.extend_from_slice(token);
+        }
+        bytes.truncate(self.max_bytes);
+
+        let barrier = bytes
+            .iter()
+            .position(|&b| self.is_barrier(b))
+            .unwrap_or(bytes.len());
+        // longer prefixes contain the barriers of shorter ones
+        let mut candidates = (1..=bytes.len().min(barrier + 1)).filter_map(|len| {
+            let range = index.tokens_ending_with(&bytes[..len])?;
+            ((range.upper - range.lower) as usize <= self.max_candidates).then_some((len, range))
+        });
+        let (len, suffix_rank_range) = match self.prefer {
+            HealingPreference::Deepest => candidates.next_back(),
+            HealingPreference::Shallowest => candidates.next(),
+        }?;
+
+        let mut num_removed_tokens = 0;
+        let mut remaining = len;
+        let mut split_offset = 0;
+        for &id in tokens {
+            let len = index.get(id).map_or(0, <[u8]>::len);
+            num_removed_tokens += 1;
+            if remaining <= len {
+                split_offset = remaining;
+                break;
+            }
+            remaining -= len;
+        }
+        Some(FimSuffixDecision {
+            num_removed_tokens,
+            split_offset,
+            removed_bytes: bytes[..len].to_vec(),
+            suffix_rank_range,
+        })
+    }
+}
+
+fn locate<P: PrefixIndex>(
+    index: &P,
+    tokens: &[usize],
+    removed_bytes: SmallToken,
+    rank_range: SortedTokenRange,
+) -> HealingDecision {
+    let mut num_removed_tokens = 0;
+    let mut remaining = removed_bytes.len();
+    let mut split_offset = 0;
+    for &id in tokens.iter().rev() {
+        let len = index.get(id).map_or(0, <[u8]>::len);
+        num_removed_tokens += 1;
+        if remaining <= len {
+            split_offset = len - remaining;
+            break;
+        }
+        remaining -= len;
+    }
+    HealingDecision {
+        num_removed_tokens,
+        split_offset,
+        removed_bytes: removed_bytes.to_vec(),
+        rank_range,
+    }
+}
+
+#[cfg(feature = "pyo3")]
+mod _pyo3 {
+    use std::collections::BTreeSet;
+
+    use pyo3::{Python, pymethods};
+
+    use super::{FimHealingDecision, HealingDecision, HealingPolicy, HealingPreference};
+    use crate::{TokenId, VocabPrefixAutomaton};
+
+    #[pymethods]
+    impl HealingPolicy {
+        #[new]
+        #[pyo3(signature=(
+            max_tokens=usize::MAX,
+            max_bytes=usize::MAX,
+            heal_through_whitespace=true,
+            heal_through_newline=true,
+            max_candidates=usize::MAX,
+            prefer=HealingPreference::Deepest,
+            barrier_token_ids=BTreeSet::new(),
+        ))]
+        fn py_new(
+            max_tokens: usize,
+            max_bytes: usize,
+            heal_through_whitespace: bool,
+            heal_through_newline: bool,
+            max_candidates: usize,
+            prefer: HealingPreference,
+            barrier_token_ids: BTreeSet<TokenId>,
+        ) -> Self {
+            Self {
+                max_tokens,
+                max_bytes,
+                heal_through_whitespace,
+                heal_through_newline,
+                max_candidates,
+                prefer,
+                barrier_token_ids,
+            }
+        }
+
+        #[staticmethod]
+        #[pyo3(name = "permissive")]
+        fn permissive_py() -> Self {
+            Self::permissive()
+        }
+
+        #[staticmethod]
+        #[pyo3(name = "conservative")]
+        fn conservative_py() -> Self {
+            Self::conservative()
+        }
+
+        #[staticmethod]
+        #[pyo3(name = "line_local")]
+        fn line_local_py() -> Self {
+            Self::line_local()
+        }
+
+        #[pyo3(name = "decide")]
+        #[pyo3(signature=(automaton, tokens, min_index=0))]
+        fn decide_py(
+            &self,
+            py: Python<'_>,
+            automaton: &VocabPrefixAutomaton,
+            tokens: Vec<usize>,
+            min_index: usize,
+        ) -> Option<HealingDecision> {
+            py.detach(|| self.decide_from(automaton, &tokens, min_index))
+        }
+
+        #[pyo3(name = "decide_fim")]
+        fn decide_fim_py(
+            &self,
+            py: Python<'_>,
+            automaton: &VocabPrefixAutomaton,
+            prefix_tokens: Vec<usize>,
+            suffix_tokens: Vec<usize>,
+        ) -> FimHealingDecision {
+            py.detach(|| self.decide_fim(automaton, &prefix_tokens, &suffix_tokens))
+        }
+    }
+}
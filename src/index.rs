@@ -0,0 +1,301 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+
+use crate::token::sort_vocab;
+use crate::{
+    ForwardPrefixCursor, PrefixCursor, SmallToken, SortedTokenId, SortedTokenRange, TokenArena,
+    TokenId, is_utf8_char_boundary,
+};
+
+/// Queries on tokens sorted by their reversed bytes,
+/// needed to heal the start of a suffix segment.
+pub trait TokenSuffixIndex {
+    /// Original ids of tokens sorted by their reversed bytes, `None` if not available.
+    fn suffix_order(&self) -> Option<&[TokenId]>;
+
+    /// The range in [`TokenSuffixIndex::suffix_order`] of tokens ending with `bytes`.
+    fn tokens_ending_with(&self, bytes: &[u8]) -> Option<SortedTokenRange>;
+}
+
+/// Queries on a vocab needed to find the tokens prefixed with a string,
+/// where the string is fed byte by byte from its end to its start.
+pub trait PrefixIndex {
+    type State: Clone + Debug;
+
+    fn vocab(&self) -> &TokenArena;
+
+    fn order(&self) -> &[TokenId];
+
+    fn rank(&self) -> &[SortedTokenId];
+
+    /// The state of the empty string.
+    fn root_state(&self) -> Self::State;
+
+    /// Prepends `byte` to the string of `state`.
+    fn feed_rev(&self, state: &mut Self::State, byte: u8);
+
+    /// Whether no more tokens can be found by prepending bytes to `state`.
+    fn is_nil(&self, state: &Self::State) -> bool;
+
+    /// The range of sorted tokens prefixed with the string of `state`.
+    fn rank_range(&self, state: &Self::State) -> Option<SortedTokenRange>;
+
+    fn get(&self, index: usize) -> Option<&[u8]> {
+        self.vocab().get(index).filter(|t| !t.is_empty())
+    }
+
+    fn cursor(&self) -> PrefixCursor<'_, Self>
+    where
+        Self: Sized,
+    {
+        PrefixCursor::new(self)
+    }
+
+    fn forward_cursor(&self) -> ForwardPrefixCursor<'_, Self>
+    where
+        Self: Sized,
+    {
+        ForwardPrefixCursor::new(self)
+    }
+
+    /// Original ids of tokens equal to `bytes`, in ascending order.
+    fn token_ids_of<B: AsRef<[u8]>>(&self, bytes: B) -> &[TokenId]
+    where
+        Self: Sized,
+    {
+        let bytes = bytes.as_ref();
+        if bytes.is_empty() {
+            return &[];
+        }
+        let mut cursor = self.forward_cursor();
+        cursor.feed_bytes(bytes);
+        if cursor.num_bytes() < bytes.len() {
+            return &[];
+        }
+        cursor.exact_token_ids()
+    }
+
+    /// Concatenates the bytes of tokens, `None` if any id is unknown.
+    fn decode<S: IntoIterator<Item = usize>>(&self, token_ids: S) -> Option<Vec<u8>> {
+        let mut res = Vec::new();
+        for id in token_ids {
+            res.extend_from_slice(self.vocab().get(id)?);
+        }
+        Some(res)
+    }
+
+    /// Like [`PrefixIndex::decode`], also returning where each token starts,
+    /// followed by the total length.
+    fn decode_with_offsets<S: IntoIterator<Item = usize>>(
+        &self,
+        token_ids: S,
+    ) -> Option<(Vec<u8>, Vec<usize>)> {
+        let mut res = Vec::new();
+        let mut offsets = vec![0];
+        for id in token_ids {
+            res.extend_from_slice(self.vocab().get(id)?);
+            offsets.push(res.len());
+        }
+        Some((res, offsets))
+    }
+
+    fn parse_bytes<B: AsRef<[u8]>>(
+        &self,
+        bytes: B,
+        start_from: usize,
+    ) -> Vec<(usize, SortedTokenRange)>
+    where
+        Self: Sized,
+    {
+        let bytes = bytes.as_ref();
+
+        let mut cursor = self.cursor();
+        let mut res = Vec::new();
+
+        for (pos, &byte) in bytes
+            .iter()
+            .enumerate()
+            .rev()
+            .take_while(|(pos, _)| *pos >= start_from)
+        {
+            let cnt_info = cursor.feed(byte);
+            if cursor.is_nil() {
+                break;
+            }
+            if let Some(cnt_info) = cnt_info {
+                res.push((pos, cnt_info));
+            }
+        }
+
+        res
+    }
+
+    fn parse_rev_token_id_seq<S: IntoIterator<Item = usize>>(
+        &self,
+        rev_tokens: S,
+    ) -> Vec<(SmallToken, SortedTokenRange)>
+    where
+        Self: Sized,
+    {
+        let mut cursor = self.cursor();
+        let mut res = Vec::new();
+        let mut bytes_rev = Vec::new();
+
+        for id in rev_tokens {
+            if cursor.is_nil() {
+                break;
+            }
+            let Some(token) = self.get(id) else {
+                break;
+            };
+            for &byte in token.iter().rev() {
+                let cnt_info = cursor.feed(byte);
+                bytes_rev.push(byte);
+                if cursor.is_nil() {
+                    break;
+                }
+                if let Some(cnt_info) = cnt_info {
+                    let mut bytes = bytes_rev.clone();
+                    bytes.reverse();
+                    res.push((SmallToken::from(bytes.as_slice()), cnt_info));
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Like [`PrefixIndex::parse_bytes`], keeping only positions
+    /// not splitting a UTF-8 character.
+    fn parse_bytes_at_char_boundaries<B: AsRef<[u8]>>(
+        &self,
+        bytes: B,
+        start_from: usize,
+    ) -> Vec<(usize, SortedTokenRange)>
+    where
+        Self: Sized,
+    {
+        let bytes = bytes.as_ref();
+        let mut res = self.parse_bytes(bytes, start_from);
+        res.retain(|&(pos, _)| is_utf8_char_boundary(bytes[pos]));
+        res
+    }
+
+    /// Like [`PrefixIndex::parse_rev_token_id_seq`], keeping only suffixes
+    /// not starting in the middle of a UTF-8 character.
+    fn parse_rev_token_id_seq_at_char_boundaries<S: IntoIterator<Item = usize>>(
+        &self,
+        rev_tokens: S,
+    ) -> Vec<(SmallToken, SortedTokenRange)>
+    where
+        Self: Sized,
+    {
+        let mut res = self.parse_rev_token_id_seq(rev_tokens);
+        res.retain(|(bytes, _)| bytes.first().copied().is_some_and(is_utf8_char_boundary));
+        res
+    }
+}
+
+/// A [`PrefixIndex`] binary searching the sorted vocab,
+/// storing nothing but the tokens and their order.
+#[derive(Clone, Debug)]
+pub struct SortedVocabIndex {
+    vocab: TokenArena,
+    order: Vec<TokenId>,
+    rank: Vec<SortedTokenId>,
+    max_token_len: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SortedVocabIndexState {
+    bytes_rev: SmallToken,
+    rank_range: Option<SortedTokenRange>,
+}
+
+impl SortedVocabIndex {
+    pub fn new<T: AsRef<[u8]>, V: IntoIterator<Item = T>>(vocab: V) -> Self {
+        let vocab = TokenArena::new(vocab);
+        let sort_result = sort_vocab(&vocab);
+        let max_token_len = vocab.iter().map(<[u8]>::len).max().unwrap_or(0);
+        Self {
+            vocab,
+            order: sort_result.order,
+            rank: sort_result.rank,
+            max_token_len,
+        }
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.vocab.heap_size()
+            + self.order.capacity() * size_of::<TokenId>()
+            + self.rank.capacity() * size_of::<SortedTokenId>()
+    }
+
+    fn search_prefix_rev(&self, bytes_rev: &[u8]) -> Option<SortedTokenRange> {
+        // `Equal` if the token is prefixed with the bytes
+        let cmp = |&id: &TokenId| {
+            let token = self.vocab.get(id as usize).unwrap_or_default();
+            let common_cmp = token
+                .iter()
+                .zip(bytes_rev.iter().rev())
+                .map(|(a, b)| a.cmp(b))
+                .find(|o| o.is_ne());
+            match common_cmp {
+                Some(o) => o,
+                None if token.len() < bytes_rev.len() => Ordering::Less,
+                None => Ordering::Equal,
+            }
+        };
+        let lower = self.order.partition_point(|id| cmp(id).is_lt());
+        let upper = lower + self.order[lower..].partition_point(|id| cmp(id).is_eq());
+        (lower < upper).then_some(SortedTokenRange {
+            lower: lower as SortedTokenId,
+            upper: upper as SortedTokenId,
+        })
+    }
+}
+
+impl PrefixIndex for SortedVocabIndex {
+    type State = SortedVocabIndexState;
+
+    fn vocab(&self) -> &TokenArena {
+        &self.vocab
+    }
+
+    fn order(&self) -> &[TokenId] {
+        &self.order
+    }
+
+    fn rank(&self) -> &[SortedTokenId] {
+        &self.rank
+    }
+
+    fn root_state(&self) -> Self::State {
+        SortedVocabIndexState {
+            bytes_rev: SmallToken::new(),
+            rank_range: self.search_prefix_rev(&[]),
+        }
+    }
+
+    fn feed_rev(&self, state: &mut Self::State, byte: u8) {
+        if self.is_nil(state) {
+            return;
+        }
+        state.bytes_rev.push(byte);
+        state.rank_range = if self.is_nil(state) {
+            None
+        } else {
+            self.search_prefix_rev(&state.bytes_rev)
+        };
+    }
+
+    fn is_nil(&self, state: &Self::State) -> bool {
+        // unlike a suffix automaton, this index can not tell whether
+        // prepending bytes to a string prefixing no token would help
+        state.bytes_rev.len() > self.max_token_len
+    }
+
+    fn rank_range(&self, state: &Self::State) -> Option<SortedTokenRange> {
+        state.rank_range.clone()
+    }
+}
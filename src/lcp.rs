@@ -0,0 +1,71 @@
+use crate::{SortedTokenRange, TokenArena, TokenId};
+
+/// Longest common prefixes of adjacent sorted tokens,
+/// with a sparse table answering range minimum queries in O(1).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LcpIndex {
+    // levels[k][i] is the minimum of lcp[i..i + 2^k]
+    levels: Vec<Vec<u32>>,
+}
+
+impl LcpIndex {
+    pub fn new(vocab: &TokenArena, order: &[TokenId]) -> Self {
+        let token = |&id: &TokenId| vocab.get(id as usize).unwrap_or_default();
+        let lcp: Vec<u32> = order
+            .windows(2)
+            .map(|w| {
+                token(&w[0])
+                    .iter()
+                    .zip(token(&w[1]))
+                    .take_while(|(a, b)| a == b)
+                    .count() as u32
+            })
+            .collect();
+
+        let mut levels = vec![lcp];
+        loop {
+            let width = 1 << (levels.len() - 1);
+            let last = levels.last().expect("levels are not empty");
+            if last.len() <= width {
+                break;
+            }
+            let next = (0..last.len() - width)
+                .map(|i| last[i].min(last[i + width]))
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// Minimum of `lcp[lower..upper]`, where `lower < upper`.
+    fn min(&self, lower: usize, upper: usize) -> Option<u32> {
+        let k = (upper - lower).ilog2() as usize;
+        let level = self.levels.get(k)?;
+        Some((*level.get(lower)?).min(*level.get(upper - (1 << k))?))
+    }
+
+    /// Length of the longest common prefix of all tokens in `range`.
+    pub fn common_prefix_len(
+        &self,
+        vocab: &TokenArena,
+        order: &[TokenId],
+        range: &SortedTokenRange,
+    ) -> Option<usize> {
+        let (lower, upper) = (range.lower as usize, range.upper as usize);
+        if lower >= upper || upper > order.len() {
+            return None;
+        }
+        if lower + 1 == upper {
+            return vocab.get(order[lower] as usize).map(<[u8]>::len);
+        }
+        self.min(lower, upper - 1).map(|len| len as usize)
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.levels
+            .iter()
+            .map(|level| level.capacity() * size_of::<u32>())
+            .sum::<usize>()
+            + self.levels.capacity() * size_of::<Vec<u32>>()
+    }
+}
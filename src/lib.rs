@@ -10,11 +10,44 @@
 //! Thus finding tokens prefixed with a string
 //! is the same as walking to the state on the suffix automaton
 //! and gathering information among the subtree of the link tree.
+mod arena;
 mod automaton;
+mod cursor;
+mod healing;
+mod index;
+mod lcp;
+mod mutable_token_seq_trie;
+mod prefix_cache;
+mod suffix;
 mod token;
+mod token_seq_trie;
+mod transition;
+mod utf8;
+mod vocab_trie;
 
-pub use crate::automaton::VocabPrefixAutomaton;
+pub use crate::arena::{TokenArena, TokenArenaIter};
+pub use crate::automaton::{AutomatonOptions, VocabPrefixAutomaton};
+pub use crate::cursor::{ForwardPrefixCursor, PrefixCursor, PrefixCursorCheckpoint};
+pub use crate::healing::{
+    FimHealingDecision, FimSuffixDecision, HealingDecision, HealingPolicy, HealingPreference,
+};
+pub use crate::index::{PrefixIndex, SortedVocabIndex, SortedVocabIndexState, TokenSuffixIndex};
+pub use crate::mutable_token_seq_trie::{
+    MutableTokenSeqTrie, MutableTokenSeqTrieNodeId, TokenSeqTrieLayout,
+};
+pub use crate::prefix_cache::{
+    PREFIX_CACHE_ROOT_NODE_ID, PrefixCache, PrefixCacheNodeId, PrefixMatch,
+};
 pub use crate::token::{SmallToken, SortedTokenId, SortedTokenRange, TokenId};
+pub use crate::token_seq_trie::{
+    AncestorIndex, TokenSeqChildOrder, TokenSeqChunk, TokenSeqSegment, TokenSeqTrie,
+    TokenSeqTrieNode, TokenSeqTrieOptions, TokenSeqValuePolicy, ancestor_mask,
+    packed_ancestor_mask, prefill_chunks, reduce_subtrees, segments, token_path,
+};
+pub use crate::utf8::{
+    Utf8Class, Utf8ClassColumns, Utf8Constraint, is_utf8_char_boundary, split_utf8,
+};
+pub use crate::vocab_trie::{VOCAB_TRIE_ROOT_NODE_ID, VocabTrie, VocabTrieNodeId};
 
 #[cfg(test)]
 mod tests;
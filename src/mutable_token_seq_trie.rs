@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+
+use crate::TokenId;
+
+/// A node of a [`MutableTokenSeqTrie`], tagged with the generation of its slot
+/// so that ids of pruned nodes never refer to nodes reusing the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "pyo3",
+    pyo3::pyclass(get_all, frozen, eq, hash, from_py_object)
+)]
+pub struct MutableTokenSeqTrieNodeId {
+    pub index: usize,
+    pub generation: u64,
+}
+
+#[derive(Clone, Debug)]
+struct MutableNode<V> {
+    parent: MutableTokenSeqTrieNodeId,
+    token: TokenId,
+    children: BTreeMap<TokenId, MutableTokenSeqTrieNodeId>,
+    value: Option<V>,
+}
+
+#[derive(Clone, Debug)]
+struct MutableSlot<V> {
+    generation: u64,
+    node: Option<MutableNode<V>>,
+}
+
+/// A token trie growing and shrinking in place,
+/// where node ids stay valid until their nodes are pruned, and are never reused afterwards.
+#[derive(Clone, Debug)]
+pub struct MutableTokenSeqTrie<V> {
+    nodes: Vec<MutableSlot<V>>,
+    free: Vec<usize>,
+    roots: BTreeMap<TokenId, MutableTokenSeqTrieNodeId>,
+}
+
+impl<V> Default for MutableTokenSeqTrie<V> {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            roots: BTreeMap::new(),
+        }
+    }
+}
+
+/// Live nodes of a [`MutableTokenSeqTrie`] in DFS order, laid out like a
+/// [`TokenSeqTrie`](crate::TokenSeqTrie) with inclusive `subtree_upper_seq`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct TokenSeqTrieLayout {
+    pub node_ids: Vec<MutableTokenSeqTrieNodeId>,
+    pub parents: Vec<usize>,
+    pub subtree_lower_seq: Vec<usize>,
+    pub subtree_upper_seq: Vec<usize>,
+    pub depths: Vec<usize>,
+    pub tokens: Vec<TokenId>,
+}
+
+impl TokenSeqTrieLayout {
+    pub fn len(&self) -> usize {
+        self.node_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.node_ids.is_empty()
+    }
+}
+
+impl<V> MutableTokenSeqTrie<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of live nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, node: MutableTokenSeqTrieNodeId) -> bool {
+        self.get(node).is_some()
+    }
+
+    pub fn parent(&self, node: MutableTokenSeqTrieNodeId) -> Option<MutableTokenSeqTrieNodeId> {
+        self.get(node)
+            .map(|n| n.parent)
+            .filter(|&parent| parent != node)
+    }
+
+    pub fn token(&self, node: MutableTokenSeqTrieNodeId) -> Option<TokenId> {
+        self.get(node).map(|n| n.token)
+    }
+
+    /// Children of the node, or roots for `None`, in ascending order of tokens.
+    pub fn children(
+        &self,
+        node: Option<MutableTokenSeqTrieNodeId>,
+    ) -> impl Iterator<Item = MutableTokenSeqTrieNodeId> + '_ {
+        let children = match node {
+            None => Some(&self.roots),
+            Some(node) => self.get(node).map(|n| &n.children),
+        };
+        children.into_iter().flat_map(|c| c.values().copied())
+    }
+
+    pub fn value(&self, node: MutableTokenSeqTrieNodeId) -> Option<&V> {
+        self.get(node)?.value.as_ref()
+    }
+
+    /// Returns the value it replaces.
+    pub fn set_value(&mut self, node: MutableTokenSeqTrieNodeId, value: Option<V>) -> Option<V> {
+        let n = self.get_mut(node)?;
+        std::mem::replace(&mut n.value, value)
+    }
+
+    /// Appends a child to the node, or a root for `None`,
+    /// reusing the existing one with the same token.
+    ///
+    /// Returns `None` if the node has been pruned.
+    pub fn add_child(
+        &mut self,
+        node: Option<MutableTokenSeqTrieNodeId>,
+        token: TokenId,
+    ) -> Option<MutableTokenSeqTrieNodeId> {
+        let id = match self.free.last() {
+            Some(&index) => MutableTokenSeqTrieNodeId {
+                index,
+                generation: self.nodes[index].generation,
+            },
+            None => MutableTokenSeqTrieNodeId {
+                index: self.nodes.len(),
+                generation: 0,
+            },
+        };
+        let children = match node {
+            None => &mut self.roots,
+            Some(node) => &mut self.get_mut(node)?.children,
+        };
+        let child = *children.entry(token).or_insert(id);
+        if child == id {
+            let parent = node.unwrap_or(id);
+            let node = Some(MutableNode {
+                parent,
+                token,
+                children: BTreeMap::new(),
+                value: None,
+            });
+            match self.free.pop() {
+                Some(index) => self.nodes[index].node = node,
+                None => self.nodes.push(MutableSlot {
+                    generation: id.generation,
+                    node,
+                }),
+            }
+        }
+        Some(child)
+    }
+
+    /// Inserts the sequence under the roots, returning its last node,
+    /// or `None` for an empty sequence.
+    ///
+    /// A `None` value keeps the existing one.
+    pub fn insert(
+        &mut self,
+        tokens: &[TokenId],
+        value: Option<V>,
+    ) -> Option<MutableTokenSeqTrieNodeId> {
+        let mut node = None;
+        for &token in tokens {
+            node = self.add_child(node, token);
+        }
+        let node = node?;
+        if value.is_some() {
+            self.set_value(node, value);
+        }
+        Some(node)
+    }
+
+    /// Removes the node with its subtree, returning the number of removed nodes.
+    pub fn prune(&mut self, node: MutableTokenSeqTrieNodeId) -> usize {
+        let Some(n) = self.get(node) else {
+            return 0;
+        };
+        let (parent, token) = (n.parent, n.token);
+        if parent == node {
+            self.roots.remove(&token);
+        } else {
+            self.get_mut(parent)
+                .expect("parents outlive their children")
+                .children
+                .remove(&token);
+        }
+
+        let mut stack = vec![node];
+        let mut num_removed = 0;
+        while let Some(id) = stack.pop() {
+            // bumping the generation invalidates the id
+            let slot = &mut self.nodes[id.index];
+            let n = slot.node.take().expect("children are alive");
+            slot.generation += 1;
+            stack.extend(n.children.into_values());
+            self.free.push(id.index);
+            num_removed += 1;
+        }
+        num_removed
+    }
+
+    /// Lays out live nodes in DFS order, visiting children in ascending order of tokens.
+    pub fn linearize(&self) -> TokenSeqTrieLayout {
+        let n = self.len();
+        let mut layout = TokenSeqTrieLayout {
+            node_ids: Vec::with_capacity(n),
+            parents: Vec::with_capacity(n),
+            subtree_lower_seq: Vec::with_capacity(n),
+            subtree_upper_seq: Vec::with_capacity(n),
+            depths: Vec::with_capacity(n),
+            tokens: Vec::with_capacity(n),
+        };
+
+        // (node, parent in the layout), pushed in reverse to pop in ascending order
+        let mut stack: Vec<_> = self.roots.values().rev().map(|&id| (id, None)).collect();
+        while let Some((id, parent)) = stack.pop() {
+            let node = self.get(id).expect("children are alive");
+            let i = layout.node_ids.len();
+            let parent = parent.unwrap_or(i);
+            layout.node_ids.push(id);
+            layout.parents.push(parent);
+            layout.subtree_lower_seq.push(i);
+            layout.subtree_upper_seq.push(i);
+            layout.depths.push(if parent == i {
+                0
+            } else {
+                layout.depths[parent] + 1
+            });
+            layout.tokens.push(node.token);
+            stack.extend(node.children.values().rev().map(|&child| (child, Some(i))));
+        }
+
+        for i in (0..layout.len()).rev() {
+            let parent = layout.parents[i];
+            if parent != i {
+                layout.subtree_upper_seq[parent] =
+                    layout.subtree_upper_seq[parent].max(layout.subtree_upper_seq[i]);
+            }
+        }
+        layout
+    }
+
+    fn get(&self, node: MutableTokenSeqTrieNodeId) -> Option<&MutableNode<V>> {
+        let slot = self.nodes.get(node.index)?;
+        (slot.generation == node.generation)
+            .then_some(slot.node.as_ref())
+            .flatten()
+    }
+
+    fn get_mut(&mut self, node: MutableTokenSeqTrieNodeId) -> Option<&mut MutableNode<V>> {
+        let slot = self.nodes.get_mut(node.index)?;
+        (slot.generation == node.generation)
+            .then_some(slot.node.as_mut())
+            .flatten()
+    }
+}
+
+#[cfg(feature = "pyo3")]
+mod _pyo3 {
+    use pyo3::pymethods;
+
+    use super::{MutableTokenSeqTrieNodeId, TokenSeqTrieLayout};
+
+    #[pymethods]
+    impl MutableTokenSeqTrieNodeId {
+        fn __repr__(&self) -> String {
+            let Self { index, generation } = self;
+            format!("MutableTokenSeqTrieNodeId(index={index}, generation={generation})")
+        }
+    }
+
+    #[pymethods]
+    impl TokenSeqTrieLayout {
+        fn __len__(&self) -> usize {
+            self.len()
+        }
+    }
+}
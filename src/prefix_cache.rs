@@ -0,0 +1,396 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+
+use crate::TokenId;
+
+/// A node of a [`PrefixCache`], tagged with the generation of its slot
+/// so that ids of evicted nodes never refer to nodes reusing the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "pyo3",
+    pyo3::pyclass(get_all, frozen, eq, hash, from_py_object)
+)]
+pub struct PrefixCacheNodeId {
+    pub index: usize,
+    pub generation: u64,
+}
+
+pub const PREFIX_CACHE_ROOT_NODE_ID: PrefixCacheNodeId = PrefixCacheNodeId {
+    index: 0,
+    generation: 0,
+};
+
+#[derive(Clone, Debug)]
+struct PrefixCacheNode<H> {
+    parent: PrefixCacheNodeId,
+    // tokens on the edge from the parent
+    tokens: Vec<TokenId>,
+    children: BTreeMap<TokenId, PrefixCacheNodeId>,
+    handle: Option<H>,
+    ref_count: usize,
+    last_access: u64,
+}
+
+impl<H> PrefixCacheNode<H> {
+    fn new(parent: PrefixCacheNodeId, tokens: Vec<TokenId>, last_access: u64) -> Self {
+        Self {
+            parent,
+            tokens,
+            children: BTreeMap::new(),
+            handle: None,
+            ref_count: 0,
+            last_access,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PrefixCacheSlot<H> {
+    generation: u64,
+    node: Option<PrefixCacheNode<H>>,
+}
+
+/// The longest cached prefix of a sequence, which may end within an edge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct PrefixMatch {
+    /// The deepest node with a handle along the prefix.
+    pub node: Option<PrefixCacheNodeId>,
+    /// Number of tokens from the root to `node`.
+    pub node_len: usize,
+    /// Number of tokens in the prefix.
+    pub len: usize,
+    /// The node whose edge the prefix ends on, or `None` for an empty prefix.
+    pub covering: Option<PrefixCacheNodeId>,
+}
+
+/// A long-lived radix tree of token sequences carrying opaque cache handles.
+///
+/// Node ids stay valid until their nodes are evicted, and are never reused afterwards.
+/// Pinned nodes are never evicted.
+#[derive(Clone, Debug)]
+pub struct PrefixCache<H> {
+    nodes: Vec<PrefixCacheSlot<H>>,
+    free: Vec<usize>,
+    num_tokens: usize,
+    clock: u64,
+}
+
+impl<H> Default for PrefixCache<H> {
+    fn default() -> Self {
+        Self {
+            nodes: vec![PrefixCacheSlot {
+                generation: PREFIX_CACHE_ROOT_NODE_ID.generation,
+                node: Some(PrefixCacheNode::new(
+                    PREFIX_CACHE_ROOT_NODE_ID,
+                    Vec::new(),
+                    0,
+                )),
+            }],
+            free: Vec::new(),
+            num_tokens: 0,
+            clock: 0,
+        }
+    }
+}
+
+impl<H> PrefixCache<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of tokens on all edges.
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    /// Number of live nodes, including the root.
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    pub fn contains(&self, node: PrefixCacheNodeId) -> bool {
+        self.get(node).is_some()
+    }
+
+    pub fn parent(&self, node: PrefixCacheNodeId) -> Option<PrefixCacheNodeId> {
+        self.get(node)
+            .map(|n| n.parent)
+            .filter(|&parent| parent != node)
+    }
+
+    pub fn handle(&self, node: PrefixCacheNodeId) -> Option<&H> {
+        self.get(node)?.handle.as_ref()
+    }
+
+    pub fn ref_count(&self, node: PrefixCacheNodeId) -> Option<usize> {
+        self.get(node).map(|n| n.ref_count)
+    }
+
+    /// Children of the node in ascending order of the first tokens of their edges.
+    pub fn children(
+        &self,
+        node: PrefixCacheNodeId,
+    ) -> impl Iterator<Item = PrefixCacheNodeId> + '_ {
+        self.get(node)
+            .into_iter()
+            .flat_map(|n| n.children.values().copied())
+    }
+
+    /// Tokens from the root to the node.
+    pub fn tokens(&self, node: PrefixCacheNodeId) -> Option<Vec<TokenId>> {
+        let mut edges = Vec::new();
+        let mut current = node;
+        while current != PREFIX_CACHE_ROOT_NODE_ID {
+            let n = self.get(current)?;
+            edges.push(n.tokens.as_slice());
+            current = n.parent;
+        }
+        Some(edges.into_iter().rev().flatten().copied().collect())
+    }
+
+    /// Attaches the handle to the node of `tokens`,
+    /// returning the node and the handle it replaces,
+    /// or `None` for an empty sequence, which the root cannot hold.
+    ///
+    /// An edge matched partially is split, so that the sequence ends right at a node.
+    pub fn insert(
+        &mut self,
+        tokens: &[TokenId],
+        handle: H,
+    ) -> Option<(PrefixCacheNodeId, Option<H>)> {
+        if tokens.is_empty() {
+            return None;
+        }
+        let tick = self.tick();
+        let mut node = PREFIX_CACHE_ROOT_NODE_ID;
+        let mut len = 0;
+        self.node_mut(node).last_access = tick;
+        while let Some(&child) = tokens
+            .get(len)
+            .and_then(|token| self.node(node).children.get(token))
+        {
+            let common_len = self.common_len(child, &tokens[len..]);
+            let child = if common_len < self.node(child).tokens.len() {
+                self.split(child, common_len)
+            } else {
+                child
+            };
+            self.node_mut(child).last_access = tick;
+            node = child;
+            len += common_len;
+        }
+
+        if len < tokens.len() {
+            let child = self.alloc(PrefixCacheNode::new(node, tokens[len..].to_vec(), tick));
+            self.node_mut(node).children.insert(tokens[len], child);
+            self.num_tokens += tokens.len() - len;
+            node = child;
+        }
+        Some((node, self.node_mut(node).handle.replace(handle)))
+    }
+
+    /// Finds the longest cached prefix of `tokens`, marking its nodes as recently used.
+    ///
+    /// The tree is left as is; the handle of `covering`, if any,
+    /// holds the whole prefix even if it ends within its edge.
+    pub fn match_prefix(&mut self, tokens: &[TokenId]) -> PrefixMatch {
+        let tick = self.tick();
+        let mut res = PrefixMatch::default();
+        let mut node = PREFIX_CACHE_ROOT_NODE_ID;
+        self.node_mut(node).last_access = tick;
+        while let Some(&child) = tokens
+            .get(res.len)
+            .and_then(|token| self.node(node).children.get(token))
+        {
+            let common_len = self.common_len(child, &tokens[res.len..]);
+            let n = self.node_mut(child);
+            n.last_access = tick;
+            res.len += common_len;
+            res.covering = Some(child);
+            if common_len < n.tokens.len() {
+                break;
+            }
+            if n.handle.is_some() {
+                res.node = Some(child);
+                res.node_len = res.len;
+            }
+            node = child;
+        }
+        res
+    }
+
+    /// Protects the node and, implicitly, its ancestors from eviction.
+    pub fn pin(&mut self, node: PrefixCacheNodeId) -> bool {
+        let Some(n) = self.get_mut(node) else {
+            return false;
+        };
+        n.ref_count += 1;
+        true
+    }
+
+    /// Returns `false` if the node is not pinned.
+    pub fn unpin(&mut self, node: PrefixCacheNodeId) -> bool {
+        match self.get_mut(node) {
+            Some(n) if n.ref_count > 0 => {
+                n.ref_count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Evicts least recently used unpinned leaves until at most `max_tokens` remain,
+    /// returning the handles of evicted nodes.
+    pub fn evict(&mut self, max_tokens: usize) -> Vec<H> {
+        let mut evicted = Vec::new();
+        let mut heap: BinaryHeap<_> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                let n = slot.node.as_ref()?;
+                let id = PrefixCacheNodeId {
+                    index,
+                    generation: slot.generation,
+                };
+                self.is_evictable(id, n)
+                    .then_some(Reverse((n.last_access, id)))
+            })
+            .collect();
+        while self.num_tokens > max_tokens
+            && let Some(Reverse((_, id))) = heap.pop()
+        {
+            let node = self.dealloc(id);
+            self.num_tokens -= node.tokens.len();
+            evicted.extend(node.handle);
+
+            let parent = self.node_mut(node.parent);
+            parent.children.remove(&node.tokens[0]);
+            let last_access = parent.last_access;
+            if self.is_evictable(node.parent, self.node(node.parent)) {
+                heap.push(Reverse((last_access, node.parent)));
+            }
+        }
+        evicted
+    }
+
+    fn is_evictable(&self, id: PrefixCacheNodeId, node: &PrefixCacheNode<H>) -> bool {
+        id != PREFIX_CACHE_ROOT_NODE_ID && node.children.is_empty() && node.ref_count == 0
+    }
+
+    /// Length of the common prefix of `tokens` and the edge to `node`.
+    fn common_len(&self, node: PrefixCacheNodeId, tokens: &[TokenId]) -> usize {
+        self.node(node)
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+
+    /// Splits the edge to `node` after `len` tokens, returning the new upper node.
+    fn split(&mut self, node: PrefixCacheNodeId, len: usize) -> PrefixCacheNodeId {
+        let n = self.node_mut(node);
+        let lower_tokens = n.tokens.split_off(len);
+        let upper_tokens = std::mem::replace(&mut n.tokens, lower_tokens);
+        let (parent, last_access) = (n.parent, n.last_access);
+
+        let first = upper_tokens[0];
+        let mut upper = PrefixCacheNode::new(parent, upper_tokens, last_access);
+        upper.children.insert(self.node(node).tokens[0], node);
+        let upper = self.alloc(upper);
+        self.node_mut(node).parent = upper;
+        self.node_mut(parent).children.insert(first, upper);
+        upper
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn alloc(&mut self, node: PrefixCacheNode<H>) -> PrefixCacheNodeId {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.nodes.push(PrefixCacheSlot {
+                    generation: 0,
+                    node: None,
+                });
+                self.nodes.len() - 1
+            }
+        };
+        let slot = &mut self.nodes[index];
+        slot.node = Some(node);
+        PrefixCacheNodeId {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    /// Frees the slot of the node, bumping its generation to invalidate the id.
+    fn dealloc(&mut self, node: PrefixCacheNodeId) -> PrefixCacheNode<H> {
+        let slot = &mut self.nodes[node.index];
+        debug_assert_eq!(slot.generation, node.generation);
+        let n = slot.node.take().expect("the node should be alive");
+        slot.generation += 1;
+        self.free.push(node.index);
+        n
+    }
+
+    fn get(&self, node: PrefixCacheNodeId) -> Option<&PrefixCacheNode<H>> {
+        let slot = self.nodes.get(node.index)?;
+        (slot.generation == node.generation)
+            .then_some(slot.node.as_ref())
+            .flatten()
+    }
+
+    fn get_mut(&mut self, node: PrefixCacheNodeId) -> Option<&mut PrefixCacheNode<H>> {
+        let slot = self.nodes.get_mut(node.index)?;
+        (slot.generation == node.generation)
+            .then_some(slot.node.as_mut())
+            .flatten()
+    }
+
+    fn node(&self, node: PrefixCacheNodeId) -> &PrefixCacheNode<H> {
+        self.get(node).expect("the node should be alive")
+    }
+
+    fn node_mut(&mut self, node: PrefixCacheNodeId) -> &mut PrefixCacheNode<H> {
+        self.get_mut(node).expect("the node should be alive")
+    }
+}
+
+#[cfg(feature = "pyo3")]
+mod _pyo3 {
+    use pyo3::pymethods;
+
+    use super::{PrefixCacheNodeId, PrefixMatch};
+
+    #[pymethods]
+    impl PrefixCacheNodeId {
+        fn __repr__(&self) -> String {
+            let Self { index, generation } = self;
+            format!("PrefixCacheNodeId(index={index}, generation={generation})")
+        }
+    }
+
+    #[pymethods]
+    impl PrefixMatch {
+        fn __repr__(&self) -> String {
+            let Self {
+                node,
+                node_len,
+                len,
+                covering,
+            } = self;
+            let repr = |id: &Option<PrefixCacheNodeId>| {
+                id.as_ref()
+                    .map_or_else(|| "None".to_owned(), PrefixCacheNodeId::__repr__)
+            };
+            let (node, covering) = (repr(node), repr(covering));
+            format!("PrefixMatch(node={node}, node_len={node_len}, len={len}, covering={covering})")
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::cmp::Ordering;
+
+use crate::{SortedTokenId, SortedTokenRange, TokenArena, TokenId};
+
+/// Tokens sorted by their reversed bytes,
+/// so that tokens ending with the same bytes are contiguous.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SuffixIndex {
+    order: Vec<TokenId>,
+}
+
+impl SuffixIndex {
+    /// `order` should be sorted by reversed bytes.
+    pub fn new(order: Vec<TokenId>) -> Self {
+        Self { order }
+    }
+
+    pub fn order(&self) -> &[TokenId] {
+        &self.order
+    }
+
+    /// The range in [`SuffixIndex::order`] of tokens ending with `bytes`.
+    pub fn range_ending_with(&self, vocab: &TokenArena, bytes: &[u8]) -> Option<SortedTokenRange> {
+        if bytes.is_empty() {
+            return None;
+        }
+        // `Equal` if the token ends with the bytes
+        let cmp = |&id: &TokenId| {
+            let token = vocab.get(id as usize).unwrap_or_default();
+            let common_cmp = token
+                .iter()
+                .rev()
+                .zip(bytes.iter().rev())
+                .map(|(a, b)| a.cmp(b))
+                .find(|o| o.is_ne());
+            match common_cmp {
+                Some(o) => o,
+                None if token.len() < bytes.len() => Ordering::Less,
+                None => Ordering::Equal,
+            }
+        };
+        let lower = self.order.partition_point(|id| cmp(id).is_lt());
+        let upper = lower + self.order[lower..].partition_point(|id| cmp(id).is_eq());
+        (lower < upper).then_some(SortedTokenRange {
+            lower: lower as SortedTokenId,
+            upper: upper as SortedTokenId,
+        })
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.order.capacity() * size_of::<TokenId>()
+    }
+}
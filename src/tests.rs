@@ -1,6 +1,13 @@
 use std::collections::BTreeSet;
 
-use crate::VocabPrefixAutomaton;
+use crate::{
+    AutomatonOptions, HealingPolicy, HealingPreference, MutableTokenSeqTrie, PrefixCache,
+    PrefixCursor, PrefixIndex, PrefixMatch, SortedTokenId, SortedTokenRange, SortedVocabIndex,
+    TokenArena, TokenId, TokenSeqChildOrder, TokenSeqSegment, TokenSeqTrie, TokenSeqTrieOptions,
+    TokenSeqValuePolicy, Utf8Class, VOCAB_TRIE_ROOT_NODE_ID, VocabPrefixAutomaton, ancestor_mask,
+    is_utf8_char_boundary, packed_ancestor_mask, prefill_chunks, reduce_subtrees, segments,
+    split_utf8, token_path,
+};
 
 fn testcase_parse_chars<T: AsRef<str>>(
     automaton: &VocabPrefixAutomaton,
@@ -90,3 +97,1067 @@ fn test_simple_vocab_prefix() {
     let texts = ["bba", "bbb", "bba", "bba", "cacab", "ccc"];
     testcase_vocab_prefix(&vocab, &texts);
 }
+
+#[test]
+fn test_token_arena() {
+    let vocab = [
+        "bb",
+        "",
+        "a somewhat long token spilling out of a tiny vec",
+        "c",
+    ];
+    let arena = TokenArena::new(vocab);
+    assert_eq!(arena.len(), vocab.len());
+    assert_eq!(arena.offsets().len(), vocab.len() + 1);
+    assert!(arena.iter().eq(vocab.iter().map(|t| t.as_bytes())));
+    assert!(
+        arena
+            .iter()
+            .rev()
+            .eq(vocab.iter().rev().map(|t| t.as_bytes()))
+    );
+    assert_eq!(arena.get(vocab.len()), None);
+
+    let automaton = VocabPrefixAutomaton::new(vocab);
+    assert_eq!(automaton.get(0), Some(b"bb".as_slice()));
+    assert_eq!(automaton.get(1), None);
+    assert!(automaton.heap_size() >= arena.heap_size());
+}
+
+fn pseudo_random_vocab(size: usize, alphabet: &[u8]) -> Vec<Vec<u8>> {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize
+    };
+    (0..size)
+        .map(|_| {
+            let len = next() % 6;
+            (0..len)
+                .map(|_| alphabet[next() % alphabet.len()])
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn test_large_vocab_sorting() {
+    let vocab = pseudo_random_vocab(2000, b"abc\xe4\xb8");
+    let automaton = VocabPrefixAutomaton::new(&vocab);
+
+    let mut expected_order: Vec<_> = (0..vocab.len() as TokenId).collect();
+    expected_order.sort_by_key(|&i| &vocab[i as usize]);
+    assert_eq!(automaton.order(), expected_order);
+    for (k, &i) in automaton.order().iter().enumerate() {
+        assert_eq!(automaton.rank()[i as usize] as usize, k);
+    }
+
+    let vocab_sorted: Vec<_> = expected_order.iter().map(|&i| &vocab[i as usize]).collect();
+    for text in [b"abcab".as_slice(), b"\xe4\xb8\xe4a", b"cccc", b"ba\xb8"] {
+        let res = automaton.parse_bytes(text, 0);
+        let expected: Vec<_> = (0..text.len())
+            .rev()
+            .map(|pos| {
+                let suffix = &text[pos..];
+                let lower = vocab_sorted.partition_point(|t| t.as_slice() < suffix);
+                let upper =
+                    lower + vocab_sorted[lower..].partition_point(|t| t.starts_with(suffix));
+                (pos, lower as SortedTokenId, upper as SortedTokenId)
+            })
+            .take_while(|&(_, lower, upper)| lower < upper)
+            .collect();
+        let res: Vec<_> = res
+            .into_iter()
+            .map(|(pos, range)| (pos, range.lower, range.upper))
+            .collect();
+        assert_eq!(res, expected);
+    }
+}
+
+#[test]
+fn test_dense_transitions() {
+    let vocab = pseudo_random_vocab(2000, b"abcdefgh");
+    let sparse = VocabPrefixAutomaton::with_options(
+        &vocab,
+        &AutomatonOptions {
+            dense_threshold: usize::MAX,
+            ..Default::default()
+        },
+    );
+    let dense = VocabPrefixAutomaton::with_options(
+        &vocab,
+        &AutomatonOptions {
+            dense_threshold: 4,
+            ..Default::default()
+        },
+    );
+    assert_eq!(sparse.num_of_dense_states(), 0);
+    assert!(dense.num_of_dense_states() > 0);
+    assert!(dense.heap_size() > sparse.heap_size());
+
+    let tokens: Vec<_> = (0..vocab.len()).collect();
+    for text in [b"abcabcha".as_slice(), b"hgfedcba", b"aaaaaaaa", b"xabc"] {
+        assert_eq!(sparse.parse_bytes(text, 0), dense.parse_bytes(text, 0));
+    }
+    for window in tokens.windows(3).step_by(50) {
+        assert_eq!(
+            sparse.parse_rev_token_id_seq(window.iter().rev().copied()),
+            dense.parse_rev_token_id_seq(window.iter().rev().copied()),
+        );
+    }
+}
+
+#[test]
+fn test_sorted_vocab_index() {
+    let vocab = pseudo_random_vocab(2000, b"abcd");
+    let automaton = VocabPrefixAutomaton::new(&vocab);
+    let index = SortedVocabIndex::new(&vocab);
+    assert_eq!(PrefixIndex::order(&index), automaton.order());
+    assert!(index.heap_size() < automaton.heap_size());
+
+    for text in [b"abcdabcd".as_slice(), b"ddddd", b"xab", b""] {
+        assert_eq!(index.parse_bytes(text, 0), automaton.parse_bytes(text, 0));
+        assert_eq!(index.parse_bytes(text, 3), automaton.parse_bytes(text, 3));
+    }
+    for k in (0..vocab.len() - 3).step_by(40) {
+        let rev_tokens = (k..k + 3).rev();
+        assert_eq!(
+            index.parse_rev_token_id_seq(rev_tokens.clone()),
+            automaton.parse_rev_token_id_seq(rev_tokens),
+        );
+    }
+}
+
+#[test]
+fn test_prefix_cursor() {
+    let vocab = ["bb", "ca", "ab", "c", "aa", "bbaa", "a", "cc", "b"];
+    let automaton = VocabPrefixAutomaton::new(vocab);
+    let text = b"cbbaa";
+
+    let mut cursor = automaton.cursor();
+    let mut res = Vec::new();
+    let mut checkpoint = None;
+    for (pos, &byte) in text.iter().enumerate().rev() {
+        if let Some(rank_range) = cursor.feed(byte) {
+            res.push((pos, rank_range));
+        }
+        if cursor.num_bytes() == 2 {
+            checkpoint = Some(cursor.checkpoint());
+        }
+    }
+    assert_eq!(res, automaton.parse_bytes(text, 0));
+
+    let checkpoint = checkpoint.unwrap();
+    cursor.restore(checkpoint.clone());
+    assert_eq!(cursor.num_bytes(), 2);
+    let aa = automaton
+        .parse_bytes("aa", 0)
+        .pop()
+        .map(|(_, rank_range)| rank_range);
+    assert_eq!(cursor.rank_range(), aa);
+
+    // "b" + "baa"
+    let mut forked = PrefixCursor::from_checkpoint(&automaton, checkpoint);
+    assert!(forked.feed_token(8));
+    assert!(!forked.feed_token(vocab.len()));
+    assert_eq!(forked.num_bytes(), 3);
+    assert_eq!(forked.rank_range(), cursor.clone().feed(b'b'));
+}
+
+#[test]
+fn test_forward_prefix_cursor() {
+    let vocab = pseudo_random_vocab(2000, b"abc");
+    let automaton = VocabPrefixAutomaton::new(&vocab);
+    let vocab_sorted: Vec<_> = automaton
+        .order()
+        .iter()
+        .map(|&i| vocab[i as usize].as_slice())
+        .collect();
+
+    for text in [b"abcab".as_slice(), b"cccc", b"bad", b"a"] {
+        let mut cursor = automaton.forward_cursor();
+        for end in 1..=text.len() {
+            let prefix = &text[..end];
+            let rank_range = cursor.feed(text[end - 1]);
+            assert_eq!(cursor.num_bytes(), end);
+
+            let lower = vocab_sorted.partition_point(|t| *t < prefix);
+            let upper = lower + vocab_sorted[lower..].partition_point(|t| t.starts_with(prefix));
+            let exact = lower + vocab_sorted[lower..upper].partition_point(|t| *t == prefix);
+            assert_eq!(
+                rank_range.map(|r| (r.lower as usize, r.upper as usize)),
+                (lower < upper).then_some((lower, upper)),
+            );
+            assert_eq!(cursor.exact_token_ids(), &automaton.order()[lower..exact]);
+            assert_eq!(cursor.exact_rank_range().is_some(), lower < exact);
+        }
+        assert_eq!(
+            automaton.forward_cursor().feed_bytes(text),
+            cursor.rank_range()
+        );
+    }
+}
+
+#[test]
+fn test_vocab_trie() {
+    let vocab = pseudo_random_vocab(500, b"abc");
+    let automaton = VocabPrefixAutomaton::new(&vocab);
+    let trie = automaton.vocab_trie();
+
+    let prefixes: BTreeSet<_> = vocab
+        .iter()
+        .flat_map(|t| (0..=t.len()).map(|i| &t[..i]))
+        .collect();
+    assert_eq!(trie.num_of_nodes(), prefixes.len());
+    assert_eq!(trie.find(b""), Some(VOCAB_TRIE_ROOT_NODE_ID));
+    assert_eq!(trie.find(b"abcx"), None);
+
+    for (node, prefix) in prefixes.iter().enumerate() {
+        // nodes are numbered in DFS order, i.e. lexicographically
+        assert_eq!(trie.find(prefix), Some(node));
+        assert_eq!(trie.bytes(node), *prefix);
+        assert_eq!(trie.depth(node), Some(prefix.len()));
+        assert_eq!(trie.byte(node), prefix.last().copied());
+        assert_eq!(
+            trie.parent(node),
+            prefix.split_last().and_then(|(_, p)| trie.find(p))
+        );
+        for &child in trie.children(node) {
+            assert_eq!(trie.parent(child), Some(node));
+            assert_eq!(trie.child(node, trie.byte(child).unwrap()), Some(child));
+        }
+
+        let mut cursor = automaton.forward_cursor();
+        let rank_range = cursor.feed_bytes(prefix).unwrap();
+        assert_eq!(trie.rank_range(node), Some(rank_range));
+        assert_eq!(trie.exact_rank_range(node), cursor.exact_rank_range());
+        assert_eq!(trie.token_ids(node), cursor.exact_token_ids());
+    }
+}
+
+#[test]
+fn test_common_prefix() {
+    let vocab = pseudo_random_vocab(2000, b"abc");
+    let options = AutomatonOptions {
+        build_lcp: true,
+        ..Default::default()
+    };
+    let automaton = VocabPrefixAutomaton::with_options(&vocab, &options);
+    let range = |lower: usize, upper: usize| SortedTokenRange {
+        lower: lower as SortedTokenId,
+        upper: upper as SortedTokenId,
+    };
+    assert_eq!(
+        VocabPrefixAutomaton::new(&vocab).common_prefix_len(&range(0, 1)),
+        None
+    );
+
+    let vocab_sorted: Vec<_> = automaton
+        .order()
+        .iter()
+        .map(|&i| vocab[i as usize].as_slice())
+        .collect();
+    let n = vocab_sorted.len();
+    for (lower, upper) in (0..n)
+        .step_by(7)
+        .flat_map(|l| [(l, l + 1), (l, (l + 13).min(n)), (l, n)])
+    {
+        let expected = vocab_sorted[lower..upper]
+            .iter()
+            .fold(vocab_sorted[lower], |p, t| {
+                let len = p.iter().zip(*t).take_while(|(a, b)| a == b).count();
+                &p[..len]
+            });
+        assert_eq!(
+            automaton.common_prefix(&range(lower, upper)),
+            Some(expected)
+        );
+    }
+    assert_eq!(automaton.common_prefix_len(&range(3, 3)), None);
+    assert_eq!(automaton.common_prefix_len(&range(0, n + 1)), None);
+
+    for text in [b"ab".as_slice(), b"ca", b"bcb"] {
+        let rank_range = automaton.forward_cursor().feed_bytes(text).unwrap();
+        assert!(
+            automaton
+                .common_prefix(&rank_range)
+                .unwrap()
+                .starts_with(text)
+        );
+    }
+}
+
+#[test]
+fn test_codec() {
+    let vocab = pseudo_random_vocab(2000, b"abcd");
+    let automaton = VocabPrefixAutomaton::new(&vocab);
+    let index = SortedVocabIndex::new(&vocab);
+
+    for (id, token) in vocab.iter().enumerate() {
+        let ids = automaton.token_ids_of(token);
+        assert_eq!(ids, index.token_ids_of(token));
+        assert_eq!(ids.contains(&(id as TokenId)), !token.is_empty());
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert!(ids.iter().all(|&i| vocab[i as usize] == *token));
+    }
+    assert!(automaton.token_ids_of(b"abcdabcd").is_empty());
+
+    let tokens = [3, 1, 4, 1, 5, 9, 2, 6];
+    let (bytes, offsets) = automaton.decode_with_offsets(tokens).unwrap();
+    assert_eq!(automaton.decode(tokens), Some(bytes.clone()));
+    assert_eq!(bytes, tokens.map(|i| vocab[i].as_slice()).concat());
+    assert_eq!(offsets.len(), tokens.len() + 1);
+    for (k, &i) in tokens.iter().enumerate() {
+        assert_eq!(bytes[offsets[k]..offsets[k + 1]], vocab[i]);
+    }
+    assert_eq!(automaton.decode([0, vocab.len()]), None);
+}
+
+#[test]
+fn test_char_boundaries() {
+    // "歌" is e6 ad 8c, "词" is e8 af 8d
+    let vocab: [&[u8]; 7] = [
+        "歌".as_bytes(),
+        b"\xad\x8c",
+        b"\x8c\xe8\xaf",
+        "歌词".as_bytes(),
+        b"\xe8",
+        b"\xe8\xaf",
+        b"a",
+    ];
+    let automaton = VocabPrefixAutomaton::new(vocab);
+    let text = b"a\xe6\xad\x8c\xe8";
+
+    let all = automaton.parse_bytes(text, 0);
+    let at_boundaries = automaton.parse_bytes_at_char_boundaries(text, 0);
+    assert!(at_boundaries.len() < all.len());
+    assert_eq!(
+        at_boundaries,
+        all.into_iter()
+            .filter(|&(pos, _)| is_utf8_char_boundary(text[pos]))
+            .collect::<Vec<_>>(),
+    );
+
+    // "a" + "歌" + "\xe8"
+    let tokens = [4, 0, 6];
+    let all = automaton.parse_rev_token_id_seq(tokens);
+    let at_boundaries = automaton.parse_rev_token_id_seq_at_char_boundaries(tokens);
+    assert!(at_boundaries.len() < all.len());
+    assert!(
+        at_boundaries
+            .iter()
+            .any(|(bytes, _)| bytes.as_slice() == b"\xe6\xad\x8c\xe8")
+    );
+    for (bytes, _) in &at_boundaries {
+        assert_eq!(split_utf8(bytes).1, b"\xe8");
+    }
+    assert_eq!(split_utf8("歌词".as_bytes()), ("歌词", b"".as_slice()));
+    assert_eq!(split_utf8(b"\xad\x8c"), ("", b"\xad\x8c".as_slice()));
+}
+
+#[test]
+fn test_utf8_classes() {
+    let cases: [(&[u8], bool, bool, u8, bool); 8] = [
+        (b"ab", false, false, 0, true),
+        (b"", false, false, 0, true),
+        ("歌词".as_bytes(), false, false, 0, false),
+        (b"\xe6\xad", false, true, 1, false),
+        (b"a\xf0", false, true, 3, false),
+        (b"\xad\x8c", true, false, 0, false),
+        (b"\x8c\xe8\xaf", true, true, 1, false),
+        (b"\xff", false, false, 0, false),
+    ];
+    for (token, starts_mid_char, ends_mid_char, num_pending_bytes, is_ascii) in cases {
+        assert_eq!(
+            Utf8Class::of(token),
+            Utf8Class {
+                starts_mid_char,
+                ends_mid_char,
+                num_pending_bytes,
+                is_ascii,
+            },
+            "{token:?}",
+        );
+    }
+
+    let vocab = cases.map(|c| c.0);
+    let automaton = VocabPrefixAutomaton::new(vocab);
+    for (id, token) in vocab.iter().enumerate() {
+        let class = Utf8Class::of(token);
+        assert_eq!(automaton.utf8_classes()[id], class);
+        assert_eq!(
+            automaton.sorted_utf8_classes()[automaton.rank()[id] as usize],
+            class
+        );
+    }
+}
+
+#[test]
+fn test_utf8_constraint() {
+    let vocab = pseudo_random_vocab(3000, b"a\x80\x8f\x90\xa0\xbf\xc3\xe0\xe6\xed\xf0\xf4\xff");
+    let automaton = VocabPrefixAutomaton::new(&vocab);
+    let constraint = automaton.utf8_constraint();
+    let is_valid = |bytes: &[u8]| match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    let flatten = |ranges: Vec<SortedTokenRange>| -> Vec<SortedTokenId> {
+        ranges.into_iter().flat_map(|r| r.lower..r.upper).collect()
+    };
+
+    let pendings: [&[u8]; 9] = [
+        b"",
+        b"\xc3",
+        b"\xe0",
+        b"\xe6",
+        b"\xe6\xad",
+        b"\xed",
+        b"\xf0",
+        b"\xf0\x90\x80",
+        b"\xf4",
+    ];
+    for pending in pendings {
+        let expected: Vec<_> = automaton
+            .order()
+            .iter()
+            .enumerate()
+            .filter(|&(_, &id)| {
+                let token = &vocab[id as usize];
+                !token.is_empty() && is_valid(&[pending, token].concat())
+            })
+            .map(|(k, _)| k as SortedTokenId)
+            .collect();
+        let allowed = constraint.allowed(pending).unwrap();
+        assert!(allowed.windows(2).all(|w| w[0].upper < w[1].lower));
+        assert_eq!(flatten(allowed), expected, "{pending:?}");
+
+        let within = SortedTokenRange {
+            lower: 500,
+            upper: 2000,
+        };
+        assert_eq!(
+            flatten(constraint.allowed_within(pending, &within).unwrap()),
+            expected
+                .iter()
+                .copied()
+                .filter(|k| (within.lower..within.upper).contains(k))
+                .collect::<Vec<_>>(),
+        );
+    }
+    for pending in [
+        b"a".as_slice(),
+        b"\x80",
+        b"\xe6\xad\x8c",
+        b"\xe0\x80",
+        b"\xff",
+    ] {
+        assert_eq!(constraint.allowed(pending), None);
+    }
+}
+
+#[test]
+fn test_healing_policy() {
+    let vocab = [
+        "hello", " wor", "ld", " world", "world", "d", "\n", "o", "lo world", " ", "l", "d\nhello",
+    ];
+    let automaton = VocabPrefixAutomaton::new(vocab);
+    let count = |bytes: &str| vocab.iter().filter(|t| t.starts_with(bytes)).count();
+    // "hello" + " wor" + "l"
+    let tokens = [0, 1, 10];
+
+    let decision = HealingPolicy::permissive()
+        .decide(&automaton, &tokens)
+        .unwrap();
+    assert_eq!(decision.removed_bytes, b"lo worl");
+    assert_eq!(decision.num_removed_tokens, 3);
+    assert_eq!(decision.split_offset, 3);
+    assert_eq!(
+        (decision.rank_range.upper - decision.rank_range.lower) as usize,
+        count("lo worl")
+    );
+
+    let no_whitespace = HealingPolicy {
+        heal_through_whitespace: false,
+        ..HealingPolicy::permissive()
+    };
+    let decision = no_whitespace.decide(&automaton, &tokens).unwrap();
+    assert_eq!(decision.removed_bytes, b" worl");
+    assert_eq!(decision.num_removed_tokens, 2);
+    assert_eq!(decision.split_offset, 0);
+
+    let shallowest = HealingPolicy {
+        prefer: HealingPreference::Shallowest,
+        ..HealingPolicy::permissive()
+    };
+    assert_eq!(
+        shallowest
+            .decide(&automaton, &tokens)
+            .unwrap()
+            .removed_bytes,
+        b"l"
+    );
+
+    let one_token = HealingPolicy {
+        max_tokens: 1,
+        ..HealingPolicy::permissive()
+    };
+    assert_eq!(
+        one_token.decide(&automaton, &tokens).unwrap().removed_bytes,
+        b"l"
+    );
+    let few_bytes = HealingPolicy {
+        max_bytes: 6,
+        ..HealingPolicy::permissive()
+    };
+    assert_eq!(
+        few_bytes.decide(&automaton, &tokens).unwrap().removed_bytes,
+        b" worl"
+    );
+    let few_candidates = HealingPolicy {
+        max_candidates: 1,
+        prefer: HealingPreference::Shallowest,
+        ..HealingPolicy::permissive()
+    };
+    assert_eq!(
+        few_candidates
+            .decide(&automaton, &tokens)
+            .unwrap()
+            .removed_bytes,
+        b"worl"
+    );
+
+    let conservative = HealingPolicy::conservative();
+    assert_eq!(
+        conservative
+            .decide(&automaton, &tokens)
+            .unwrap()
+            .removed_bytes,
+        b"l"
+    );
+    let too_vague = HealingPolicy {
+        max_candidates: 2,
+        ..conservative
+    };
+    assert_eq!(too_vague.decide(&automaton, &tokens), None);
+
+    // "ld" + "\n" + "hello"
+    let tokens = [2, 6, 0];
+    assert_eq!(
+        HealingPolicy::permissive()
+            .decide(&automaton, &tokens)
+            .unwrap()
+            .removed_bytes,
+        b"d\nhello"
+    );
+    assert_eq!(
+        HealingPolicy::line_local()
+            .decide(&automaton, &tokens)
+            .unwrap()
+            .removed_bytes,
+        b"hello"
+    );
+    assert_eq!(HealingPolicy::permissive().decide(&automaton, &[]), None);
+}
+
+#[test]
+fn test_healing_barriers() {
+    let vocab = ["<|user|>", "<|", "|>", "hi", "i", ">hi", "<|assistant|>"];
+    let automaton = VocabPrefixAutomaton::new(vocab);
+    // "<|user|>" + "hi"
+    let tokens = [0, 3];
+
+    let permissive = HealingPolicy::permissive();
+    let decision = permissive.decide(&automaton, &tokens).unwrap();
+    assert_eq!(decision.removed_bytes, b">hi");
+    assert_eq!(decision.num_removed_tokens, 2);
+
+    let protected = permissive.decide_from(&automaton, &tokens, 1).unwrap();
+    assert_eq!(protected.removed_bytes, b"hi");
+    assert_eq!(protected.num_removed_tokens, 1);
+    assert_eq!(permissive.decide_from(&automaton, &tokens, 2), None);
+    assert_eq!(permissive.decide_from(&automaton, &tokens, 5), None);
+
+    let with_barriers = HealingPolicy {
+        barrier_token_ids: [0, 6].into(),
+        ..HealingPolicy::permissive()
+    };
+    assert_eq!(with_barriers.decide(&automaton, &tokens), Some(protected));
+    assert_eq!(with_barriers.decide(&automaton, &[3, 6]), None);
+}
+
+#[test]
+fn test_fim_healing() {
+    let vocab = [
+        "def", " fo", "o(", "x)", " foo", "foo(", "(", "o", ")", "x", "oo(", " ",
+    ];
+    let options = AutomatonOptions {
+        build_suffix_index: true,
+        ..Default::default()
+    };
+    let automaton = VocabPrefixAutomaton::with_options(vocab, &options);
+
+    let suffix_order = automaton.suffix_order().unwrap();
+    let mut expected_order: Vec<_> = (0..vocab.len() as TokenId).collect();
+    expected_order.sort_by_key(|&i| vocab[i as usize].bytes().rev().collect::<Vec<_>>());
+    assert_eq!(suffix_order, expected_order);
+    for bytes in ["o", "o(", "(", "oo(", "x", "zz", ""] {
+        let mut ids = automaton.token_ids_ending_with(bytes).to_vec();
+        ids.sort();
+        let expected: Vec<_> = (0..vocab.len() as TokenId)
+            .filter(|&i| !bytes.is_empty() && vocab[i as usize].ends_with(bytes))
+            .collect();
+        assert_eq!(ids, expected, "{bytes:?}");
+    }
+
+    // "def" + " fo" | "o(" + "x)"
+    let policy = HealingPolicy::permissive();
+    let decision = policy.decide_fim(&automaton, &[0, 1], &[2, 3]);
+    assert_eq!(decision.prefix, policy.decide(&automaton, &[0, 1]));
+    assert_eq!(decision.prefix.unwrap().removed_bytes, b" fo");
+    let suffix = decision.suffix.unwrap();
+    assert_eq!(suffix.removed_bytes, b"o(");
+    assert_eq!(suffix.num_removed_tokens, 1);
+    assert_eq!(suffix.split_offset, 2);
+    let range = suffix.suffix_rank_range;
+    let mut ids = suffix_order[range.lower as usize..range.upper as usize].to_vec();
+    ids.sort();
+    assert_eq!(ids, [2, 5, 10]);
+
+    let shallowest = HealingPolicy {
+        prefer: HealingPreference::Shallowest,
+        ..HealingPolicy::permissive()
+    };
+    let suffix = shallowest
+        .decide_fim(&automaton, &[], &[2, 3])
+        .suffix
+        .unwrap();
+    assert_eq!(suffix.removed_bytes, b"o");
+    assert_eq!(suffix.split_offset, 1);
+
+    // " " + "o(" stops at the space
+    let no_whitespace = HealingPolicy {
+        heal_through_whitespace: false,
+        ..HealingPolicy::permissive()
+    };
+    let suffix = no_whitespace.decide_fim(&automaton, &[], &[11, 2]).suffix;
+    assert_eq!(suffix.unwrap().removed_bytes, b" ");
+    let with_barrier = HealingPolicy {
+        barrier_token_ids: [2].into(),
+        ..HealingPolicy::permissive()
+    };
+    assert_eq!(
+        with_barrier.decide_fim(&automaton, &[], &[2, 3]).suffix,
+        None
+    );
+
+    let without_index = VocabPrefixAutomaton::new(vocab);
+    assert_eq!(without_index.suffix_order(), None);
+    assert_eq!(
+        policy.decide_fim(&without_index, &[0, 1], &[2, 3]).suffix,
+        None
+    );
+}
+
+fn testcase_token_seq_inputs() -> [(Vec<TokenId>, Option<&'static str>); 5] {
+    [
+        (vec![3, 9, 1, 10, 9, 6, 7], Some("a")),
+        (vec![3, 9, 1, 10, 9, 5], Some("b")),
+        (vec![3, 9, 1, 10, 2], Some("c")),
+        (vec![3, 9, 1, 10], Some("d")),
+        (vec![3, 9, 1, 11], Some("e")),
+    ]
+}
+
+#[test]
+fn test_token_seq_trie() {
+    let inputs = testcase_token_seq_inputs();
+    let trie = TokenSeqTrie::new(inputs.clone());
+    let nodes = trie.nodes();
+    assert_eq!(trie.len(), 10);
+    assert_eq!(trie.prefill_chain_len(), 2);
+
+    for (seq, value) in &inputs {
+        let (i, node) = nodes
+            .iter()
+            .enumerate()
+            .find(|(_, node)| node.value == *value)
+            .unwrap();
+        assert_eq!(node.depth + 1, seq.len());
+        let path: Vec<_> = (0..=i)
+            .filter(|&j| nodes[j].subtree_lower <= i && i <= nodes[j].subtree_upper)
+            .map(|j| nodes[j].token)
+            .collect();
+        assert_eq!(&path, seq);
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        let children = nodes
+            .iter()
+            .enumerate()
+            .filter(|&(j, n)| n.parent == i && j != i)
+            .count();
+        assert_eq!(node.num_children, children);
+    }
+    assert_eq!(nodes[0].parent, 0);
+
+    let n = trie.len();
+    let mask = trie.ancestor_mask();
+    let packed = trie.packed_ancestor_mask();
+    let stride = n.div_ceil(8);
+    assert_eq!(mask.len(), n * n);
+    assert_eq!(packed.len(), n * stride);
+    for i in 0..n {
+        for j in 0..n {
+            let expected = nodes[j].subtree_lower <= i && i <= nodes[j].subtree_upper;
+            assert_eq!(mask[i * n + j], expected);
+            assert_eq!(packed[i * stride + j / 8] >> (j % 8) & 1 == 1, expected);
+        }
+    }
+    let positions = trie.position_ids(3);
+    assert!(
+        nodes
+            .iter()
+            .zip(&positions)
+            .all(|(node, &p)| p == node.depth + 3)
+    );
+    assert_eq!(ancestor_mask::<bool>(&[0, 2]), None);
+    assert_eq!(ancestor_mask::<u8>(&[0, 0]), Some(vec![1, 0, 1, 1]));
+    assert_eq!(packed_ancestor_mask(&[0, 0, 1]), Some(vec![1, 3, 7]));
+
+    let index = trie.ancestor_index();
+    assert_eq!(index.len(), n);
+    for (seq, value) in &inputs {
+        let i = nodes.iter().position(|node| node.value == *value).unwrap();
+        assert_eq!(trie.token_path(i).as_ref(), Some(seq));
+        for k in 0..seq.len() {
+            let ancestor = index.kth_ancestor(i, k).unwrap();
+            assert_eq!(trie.token_path(ancestor).unwrap(), seq[..seq.len() - k]);
+        }
+        assert_eq!(index.kth_ancestor(i, seq.len()), None);
+    }
+    for a in 0..n {
+        for b in 0..n {
+            let expected = (0..n)
+                .filter(|&j| mask[a * n + j] && mask[b * n + j])
+                .max_by_key(|&j| nodes[j].depth);
+            assert_eq!(index.lca(a, b), expected);
+        }
+    }
+    assert_eq!(index.lca(0, n), None);
+
+    assert_eq!(trie.token_path(n), None);
+    assert_eq!(token_path(&[0, 0, 1], &[5, 6, 7], 2), Some(vec![5, 6, 7]));
+    assert_eq!(token_path(&[0, 0, 1], &[5, 6, 7], 3), None);
+    assert_eq!(token_path(&[0, 2, 2], &[5, 6, 7], 1), None);
+
+    let chain = TokenSeqTrie::new([([1, 2, 3].as_slice(), Some(0)), (&[1, 2, 4], Some(1))]);
+    assert_eq!(chain.prefill_chain_len(), 1);
+    assert!(TokenSeqTrie::<()>::new::<Vec<TokenId>, _>([]).is_empty());
+}
+
+#[test]
+fn test_subtree_reduce() {
+    let trie = TokenSeqTrie::new(testcase_token_seq_inputs());
+    let nodes = trie.nodes();
+
+    assert_eq!(trie.subtree_input_counts(), [5, 5, 5, 4, 1, 2, 1, 1, 1, 1]);
+    // concatenation is not commutative, so this checks values are combined in DFS order
+    let concat = trie.reduce_subtree_values(|v| v.to_string(), |a, b| a + &b);
+    let expected: Vec<_> = nodes
+        .iter()
+        .map(|node| {
+            let all: String = nodes[node.subtree_lower..=node.subtree_upper]
+                .iter()
+                .filter_map(|n| n.value)
+                .collect();
+            (!all.is_empty()).then_some(all)
+        })
+        .collect();
+    assert_eq!(concat, expected);
+    assert_eq!(concat[0].as_deref(), Some("dcbae"));
+    assert_eq!(reduce_subtrees(&[0, 0], vec![Some(1)], |a, b| a + b), None);
+    assert_eq!(
+        reduce_subtrees(&[0, 0, 2], vec![None, Some(1), None], |a, b| a + b),
+        Some(vec![Some(1), Some(1), None])
+    );
+}
+
+#[test]
+fn test_prefill_chunks() {
+    let trie = TokenSeqTrie::new(testcase_token_seq_inputs());
+    let n = trie.len();
+    let mask = trie.ancestor_mask();
+
+    let visible: Vec<_> = trie
+        .prefill_chunks(3)
+        .unwrap()
+        .into_iter()
+        .map(|c| (c.lower, c.upper, c.visible_chunks))
+        .collect();
+    assert_eq!(
+        visible,
+        [
+            (0, 3, vec![]),
+            (3, 6, vec![0]),
+            (6, 9, vec![0, 1]),
+            (9, 10, vec![0]),
+        ]
+    );
+    for max_tokens in 1..=n + 1 {
+        let chunks = trie.prefill_chunks(max_tokens).unwrap();
+        assert_eq!(chunks.len(), n.div_ceil(max_tokens));
+        for (c, chunk) in chunks.iter().enumerate() {
+            assert!(chunk.upper - chunk.lower <= max_tokens);
+            let mask = &mask;
+            let expected: BTreeSet<_> = (chunk.lower..chunk.upper)
+                .flat_map(|i| (0..n).filter(move |&j| mask[i * n + j]))
+                .map(|j| j / max_tokens)
+                .filter(|&k| k != c)
+                .collect();
+            assert!(chunk.visible_chunks.iter().eq(&expected));
+        }
+    }
+    let roots = prefill_chunks(&[0, 1, 1], 1).unwrap();
+    assert_eq!(roots[2].visible_chunks, [1]);
+    assert_eq!(prefill_chunks(&[0, 2, 1], 2), None);
+    assert_eq!(trie.prefill_chunks(0), None);
+    assert_eq!(prefill_chunks(&[0, 0], 0), None);
+}
+
+#[test]
+fn test_token_seq_segments() {
+    let inputs = [
+        (vec![3, 9, 1, 10, 9, 6, 7], Some(0)),
+        (vec![3, 9, 1, 10, 9, 5], None),
+        (vec![3, 9, 1, 10, 2], Some(2)),
+        (vec![3, 9, 1, 10], Some(3)),
+        (vec![3, 9, 1, 11], Some(4)),
+        (vec![], Some(5)),
+    ];
+    let trie = TokenSeqTrie::new(inputs);
+    let trie_segments: Vec<_> = trie
+        .segments()
+        .into_iter()
+        .map(|s| (s.parent, s.lower, s.upper, s.inputs))
+        .collect();
+    assert_eq!(
+        trie_segments,
+        [
+            (0, 0, 3, vec![0, 1, 2, 3, 4]),
+            (0, 3, 4, vec![0, 1, 2, 3]),
+            (1, 4, 5, vec![2]),
+            (1, 5, 6, vec![0, 1]),
+            (3, 6, 7, vec![1]),
+            (3, 7, 9, vec![0]),
+            (0, 9, 10, vec![4]),
+        ]
+    );
+
+    let forest = TokenSeqTrie::new([([1, 2].as_slice(), Some(0)), (&[3], Some(1))]);
+    let roots: Vec<_> = forest.segments().iter().map(|s| s.parent).collect();
+    assert_eq!(roots, [0, 1]);
+    assert_eq!(
+        segments(&[0, 0], &[1, 0], &[Some(1)]),
+        Some(vec![TokenSeqSegment {
+            parent: 0,
+            lower: 0,
+            upper: 2,
+            inputs: vec![0],
+        }])
+    );
+    assert_eq!(segments(&[0, 2, 0], &[1, 0, 0], &[]), None);
+    assert_eq!(segments(&[0], &[0], &[Some(1)]), None);
+    assert_eq!(forest.ancestor_index().lca(1, 2), None);
+    assert!(
+        TokenSeqTrie::<()>::new::<Vec<TokenId>, _>([])
+            .segments()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_mutable_token_seq_trie() {
+    let mut trie = MutableTokenSeqTrie::new();
+    let a = trie.insert(&[1, 2, 3], Some("a")).unwrap();
+    trie.insert(&[1, 2, 4], Some("b"));
+    trie.insert(&[5], Some("c"));
+    let shared = trie.parent(a).unwrap();
+    let zero = trie.add_child(Some(shared), 0).unwrap();
+    assert_eq!(trie.add_child(Some(shared), 0), Some(zero));
+    assert_eq!(trie.len(), 6);
+
+    let layout = trie.linearize();
+    assert_eq!(layout.tokens, [1, 2, 0, 3, 4, 5]);
+    assert_eq!(layout.parents, [0, 0, 1, 1, 1, 5]);
+    assert_eq!(layout.subtree_upper_seq, [4, 4, 2, 3, 4, 5]);
+    assert_eq!(layout.depths, [0, 1, 2, 2, 2, 0]);
+    assert_eq!(layout.node_ids[3], a);
+
+    let built = TokenSeqTrie::new([
+        (vec![1, 2, 0], Some(())),
+        (vec![1, 2, 3], None),
+        (vec![1, 2, 4], None),
+        (vec![5], None),
+    ]);
+    for (i, node) in built.nodes().iter().enumerate() {
+        assert_eq!(layout.parents[i], node.parent);
+        assert_eq!(layout.subtree_lower_seq[i], node.subtree_lower);
+        assert_eq!(layout.subtree_upper_seq[i], node.subtree_upper);
+    }
+
+    assert_eq!(trie.prune(shared), 4);
+    assert!(!trie.contains(a));
+    assert_eq!(trie.prune(a), 0);
+    assert_eq!(trie.add_child(Some(a), 7), None);
+    assert_eq!(trie.linearize().tokens, [1, 5]);
+    let d = trie.insert(&[1, 6], Some("d")).unwrap();
+    assert_eq!(trie.value(d), Some(&"d"));
+    assert_eq!(trie.set_value(d, None), Some("d"));
+    assert_eq!(trie.len(), 3);
+
+    // ids of pruned nodes are not revived by nodes reusing their slots
+    let e = trie.add_child(trie.parent(d), 8).unwrap();
+    assert_eq!(trie.prune(d), 1);
+    let f = trie.add_child(trie.parent(e), 9).unwrap();
+    assert_eq!(f.index, d.index);
+    assert_ne!(f, d);
+    assert!(!trie.contains(d));
+    assert_eq!(trie.token(d), None);
+    assert_eq!(trie.set_value(d, Some("g")), None);
+    assert_eq!(trie.value(f), None);
+}
+
+#[test]
+fn test_token_seq_child_order() {
+    let inputs = [
+        (vec![5, 1], Some("a")),
+        (vec![3, 2], Some("b")),
+        (vec![5, 0], Some("c")),
+        (vec![3], Some("d")),
+    ];
+    let build = |child_order| {
+        let options = TokenSeqTrieOptions {
+            child_order,
+            ..Default::default()
+        };
+        let trie = TokenSeqTrie::with_options(inputs.clone(), &options);
+        for ((seq, value), node) in inputs.iter().zip(trie.input_nodes()) {
+            let node = node.unwrap();
+            assert_eq!(trie.token_path(node).as_ref(), Some(seq));
+            assert_eq!(trie.nodes()[node].value, *value);
+        }
+        trie
+    };
+    let tokens = |trie: &TokenSeqTrie<_>| trie.nodes().iter().map(|n| n.token).collect::<Vec<_>>();
+
+    assert_eq!(tokens(&build(TokenSeqChildOrder::TokenId)), [3, 2, 5, 0, 1]);
+
+    let insertion = build(TokenSeqChildOrder::Insertion);
+    assert_eq!(tokens(&insertion), [5, 1, 0, 3, 2]);
+    assert_eq!(
+        insertion.input_nodes(),
+        [Some(1), Some(4), Some(2), Some(3)]
+    );
+    let nodes = insertion.nodes();
+    let parents: Vec<_> = nodes.iter().map(|n| n.parent).collect();
+    let uppers: Vec<_> = nodes.iter().map(|n| n.subtree_upper).collect();
+    assert_eq!(parents, [0, 0, 0, 3, 3]);
+    assert_eq!(uppers, [2, 1, 2, 4, 4]);
+    assert_eq!(insertion.inputs_of(3), [3]);
+
+    let priority = build(TokenSeqChildOrder::Priority(vec![0, 5, 1, -2]));
+    assert_eq!(tokens(&priority), [3, 2, 5, 1, 0]);
+    let unprioritized = build(TokenSeqChildOrder::Priority(vec![]));
+    assert_eq!(tokens(&unprioritized), [3, 2, 5, 0, 1]);
+}
+
+#[test]
+fn test_token_seq_trie_duplicates() {
+    let inputs = [
+        (vec![1, 2], Some("a")),
+        (vec![1, 2, 3], Some("b")),
+        (vec![1, 2], Some("c")),
+        (vec![], Some("d")),
+        (vec![1, 2], None),
+    ];
+    let last = TokenSeqTrie::new(inputs.clone());
+    assert_eq!(
+        last.input_nodes(),
+        [Some(1), Some(2), Some(1), None, Some(1)]
+    );
+    assert_eq!(last.nodes()[1].value, Some("c"));
+    assert_eq!(last.inputs_of(1), [0, 2, 4]);
+    assert!(last.inputs_of(0).is_empty());
+    assert!(last.inputs_of(3).is_empty());
+
+    let first = TokenSeqTrie::with_policy(inputs, TokenSeqValuePolicy::First);
+    assert_eq!(first.nodes()[1].value, Some("a"));
+    assert_eq!(first.nodes()[2].value, Some("b"));
+}
+
+#[test]
+fn test_prefix_cache() {
+    let mut cache = PrefixCache::new();
+    let (a, _) = cache.insert(&[1, 2, 3, 4], "a").unwrap();
+    let (b, _) = cache.insert(&[1, 2, 5], "b").unwrap();
+    assert_eq!(cache.num_tokens(), 5);
+    assert_eq!(cache.tokens(a).unwrap(), [1, 2, 3, 4]);
+    assert_eq!(cache.tokens(b).unwrap(), [1, 2, 5]);
+    assert_eq!(cache.insert(&[1, 2, 5], "c"), Some((b, Some("b"))));
+    assert_eq!(cache.insert(&[], "d"), None);
+
+    let upper = cache.parent(a).unwrap();
+    assert_eq!(cache.tokens(upper).unwrap(), [1, 2]);
+    assert_eq!(cache.children(upper).collect::<Vec<_>>(), [a, b]);
+    let num_nodes = cache.num_nodes();
+    assert_eq!(
+        cache.match_prefix(&[1, 2, 3, 9]),
+        PrefixMatch {
+            node: None,
+            node_len: 0,
+            len: 3,
+            covering: Some(a),
+        }
+    );
+    assert_eq!(cache.num_nodes(), num_nodes);
+    assert_eq!(
+        cache.match_prefix(&[1, 2, 5, 6]),
+        PrefixMatch {
+            node: Some(b),
+            node_len: 3,
+            len: 3,
+            covering: Some(b),
+        }
+    );
+    assert_eq!(cache.match_prefix(&[7]), PrefixMatch::default());
+
+    let (c, _) = cache.insert(&[1, 2, 3], "e").unwrap();
+    assert_eq!(cache.parent(a), Some(c));
+    let PrefixMatch { node, node_len, .. } = cache.match_prefix(&[1, 2, 3, 4, 5]);
+    assert_eq!((node, node_len), (Some(a), 4));
+    assert_eq!(cache.num_tokens(), 5);
+
+    assert!(cache.pin(a));
+    assert_eq!(cache.evict(0), ["c"]);
+    assert!(!cache.contains(b));
+    assert_eq!(cache.num_tokens(), 4);
+    assert!(cache.unpin(a));
+    assert!(!cache.unpin(a));
+    assert_eq!(cache.evict(2), ["a", "e"]);
+    assert_eq!(cache.num_tokens(), 2);
+    assert_eq!(cache.num_nodes(), 2);
+
+    // ids of evicted nodes are not revived by nodes reusing their slots
+    let (x, _) = cache.insert(&[7], "x").unwrap();
+    let (y, _) = cache.insert(&[8], "y").unwrap();
+    cache.match_prefix(&[7, 7]);
+    assert_eq!(cache.evict(1), ["y"]);
+    let (z, _) = cache.insert(&[9, 9], "z").unwrap();
+    assert_eq!(z.index, y.index);
+    assert_ne!(z, y);
+    assert!(!cache.contains(y));
+    assert!(!cache.pin(y));
+    assert_eq!(cache.handle(y), None);
+    assert_eq!(cache.handle(x), Some(&"x"));
+}
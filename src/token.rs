@@ -1,11 +1,11 @@
-use std::convert::Infallible;
-
 use general_sam::{
-    BTreeTransTable, BoxBisectTable, GeneralSam, SAM_ROOT_NODE_ID, TransitionTable, TravelEvent,
-    Trie, TrieNodeAlike,
+    BoxBisectTable, ConstructiveTransitionTable, GeneralSam, GeneralSamNodeID, SAM_ROOT_NODE_ID,
+    TransitionTable, TrieNodeAlike,
 };
 use tinyvec::TinyVec;
 
+use crate::TokenArena;
+
 pub type TokenId = u32;
 pub type SortedTokenId = u32;
 
@@ -47,81 +47,199 @@ mod _pyo3 {
     }
 }
 
-pub(crate) fn build_sam_of_reversed_tokens<
-    I: Ord + Clone,
-    T: AsRef<[I]>,
-    V: IntoIterator<Item = T>,
->(
-    vocab: V,
-) -> GeneralSam<BoxBisectTable<I>> {
-    let trie_of_rev_tokens = {
-        let mut trie = Trie::<BTreeTransTable<_>>::default();
-        vocab.into_iter().for_each(|token| {
-            trie.insert(token.as_ref().iter().cloned().rev());
-        });
-        trie
-    };
-    GeneralSam::<BTreeTransTable<_>>::from_trie(trie_of_rev_tokens.get_root_state())
-        .alter_trans_table_into()
+#[cfg(feature = "rayon")]
+pub(crate) use rayon::join;
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) fn join<A: FnOnce() -> RA, B: FnOnce() -> RB, RA, RB>(a: A, b: B) -> (RA, RB) {
+    (a(), b())
 }
 
-#[derive(Debug)]
-pub(crate) struct SortResult {
-    pub rank_ranges: Vec<SortedTokenRange>,
-    pub order: Vec<TokenId>,
-    pub rank: Vec<SortedTokenId>,
+/// Transitions kept in a sorted vector,
+/// which is much lighter than a `BTreeMap` while building the automaton.
+#[derive(Clone, Debug, Default)]
+struct SortedVecTransTable(Vec<(u8, GeneralSamNodeID)>);
+
+type SortedVecTransTableIter<'a> = std::iter::Map<
+    std::slice::Iter<'a, (u8, GeneralSamNodeID)>,
+    fn(&'a (u8, GeneralSamNodeID)) -> (u8, &'a GeneralSamNodeID),
+>;
+
+impl TransitionTable for SortedVecTransTable {
+    type KeyType = u8;
+    type IterType<'a> = SortedVecTransTableIter<'a>;
+
+    fn from_kv_iter<'b, Iter: IntoIterator<Item = (u8, &'b GeneralSamNodeID)>>(iter: Iter) -> Self {
+        let mut inner: Vec<_> = iter.into_iter().map(|(k, &v)| (k, v)).collect();
+        inner.sort_unstable_by_key(|&(k, _)| k);
+        Self(inner)
+    }
+
+    fn get(&self, key: &u8) -> Option<&GeneralSamNodeID> {
+        let i = self.0.binary_search_by_key(key, |&(k, _)| k).ok()?;
+        Some(&self.0[i].1)
+    }
+
+    fn get_mut(&mut self, key: &u8) -> Option<&mut GeneralSamNodeID> {
+        let i = self.0.binary_search_by_key(key, |&(k, _)| k).ok()?;
+        Some(&mut self.0[i].1)
+    }
+
+    fn iter(&self) -> Self::IterType<'_> {
+        self.0.iter().map(|(k, v)| (*k, v))
+    }
 }
 
-pub(crate) fn sort_vocab_with_trie<I: Ord + Clone, T: AsRef<[I]>, V: IntoIterator<Item = T>>(
-    vocab: V,
-) -> SortResult {
-    let (trie, trie_node_ids) = {
-        let mut trie = Trie::<BTreeTransTable<_>>::default();
-        let trie_node_ids: Vec<_> = vocab
-            .into_iter()
-            .map(|token| trie.insert(token.as_ref().iter().cloned()))
-            .collect();
-        (trie, trie_node_ids)
-    };
+impl ConstructiveTransitionTable for SortedVecTransTable {
+    fn insert(&mut self, key: u8, trans: GeneralSamNodeID) {
+        match self.0.binary_search_by_key(&key, |&(k, _)| k) {
+            Ok(i) => self.0[i].1 = trans,
+            Err(i) => self.0.insert(i, (key, trans)),
+        }
+    }
+}
 
-    let vocab_size = trie_node_ids.len();
+/// Below this many ids, [`radix_sort_token_ids`] falls back to a comparison
+/// sort.
+const RADIX_SORT_CUTOFF: usize = 64;
 
-    let mut rank_range_in_trie = vec![SortedTokenRange::default(); trie.num_of_nodes()];
-    let mut cnt_tokens_in_trie = vec![0 as SortedTokenId; trie.num_of_nodes()];
-    trie_node_ids
-        .iter()
-        .for_each(|&i| cnt_tokens_in_trie[i] += 1);
+/// Stably sorts token ids by the byte sequences returned from `key`,
+/// bucketing by one byte at a time from the most significant one.
+fn radix_sort_token_ids<K: Iterator<Item = u8>, F: Fn(TokenId) -> K>(ids: &mut [TokenId], key: F) {
+    let mut buf = vec![0; ids.len()];
+    let mut stack = vec![(0, ids.len(), 0)];
+
+    while let Some((lower, upper, depth)) = stack.pop() {
+        let ids = &mut ids[lower..upper];
+        if ids.len() <= RADIX_SORT_CUTOFF {
+            ids.sort_by(|&a, &b| key(a).skip(depth).cmp(key(b).skip(depth)));
+            continue;
+        }
 
-    let mut tot_cnt: SortedTokenId = 0;
+        // bucket 0 holds tokens ending right at `depth`
+        let bucket_of = |id| key(id).nth(depth).map_or(0, |b| b as usize + 1);
+        let mut bucket_lower = [0; 258];
+        ids.iter()
+            .for_each(|&id| bucket_lower[bucket_of(id) + 1] += 1);
+        for b in 1..bucket_lower.len() {
+            bucket_lower[b] += bucket_lower[b - 1];
+        }
 
-    let res = trie.get_root_state().dfs_travel(|event| {
-        match event {
-            TravelEvent::PushRoot(state) | TravelEvent::Push(state, _, _) => {
-                let id = state.node_id;
-                let rank_range = &mut rank_range_in_trie[id];
-                rank_range.lower = tot_cnt;
-                tot_cnt += cnt_tokens_in_trie[id];
-            }
-            TravelEvent::Pop(state, _) => {
-                let id = state.node_id;
-                let rank_range = &mut rank_range_in_trie[id];
-                rank_range.upper = tot_cnt;
+        let mut cursor = bucket_lower;
+        let buf = &mut buf[..ids.len()];
+        ids.iter().for_each(|&id| {
+            let b = bucket_of(id);
+            buf[cursor[b]] = id;
+            cursor[b] += 1;
+        });
+        ids.copy_from_slice(buf);
+
+        for b in 1..=256 {
+            let (bucket_lower, bucket_upper) = (bucket_lower[b], bucket_lower[b + 1]);
+            if bucket_upper - bucket_lower > 1 {
+                stack.push((lower + bucket_lower, lower + bucket_upper, depth + 1));
             }
         }
-        Ok::<_, Infallible>(())
-    });
-    match res {
-        Ok(()) => {}
-        Err(e) => match e {},
     }
+}
 
-    let rank_ranges: Vec<_> = (0..vocab_size)
-        .map(|i| rank_range_in_trie[trie_node_ids[i]].clone())
-        .collect();
+/// The prefix tree of reversed tokens,
+/// implied by token ids sorted by their reversed bytes.
+#[derive(Clone, Debug)]
+struct SortedRevTokensTrieNode<'a> {
+    vocab: &'a TokenArena,
+    ids: &'a [TokenId],
+    depth: usize,
+}
+
+impl SortedRevTokensTrieNode<'_> {
+    fn byte_at(&self, id: TokenId) -> Option<u8> {
+        let token = self.vocab.get(id as usize).unwrap_or_default();
+        token.len().checked_sub(self.depth + 1).map(|i| token[i])
+    }
+}
+
+impl<'a> TrieNodeAlike for SortedRevTokensTrieNode<'a> {
+    type InnerType = u8;
+    type NextStateIter = SortedRevTokensTrieNextStates<'a>;
+
+    fn is_accepting(&self) -> bool {
+        self.ids
+            .first()
+            .is_some_and(|&id| self.byte_at(id).is_none())
+    }
+
+    fn next_states(self) -> Self::NextStateIter {
+        let num_ended = self.ids.partition_point(|&id| self.byte_at(id).is_none());
+        SortedRevTokensTrieNextStates {
+            node: Self {
+                ids: &self.ids[num_ended..],
+                ..self
+            },
+        }
+    }
+}
+
+struct SortedRevTokensTrieNextStates<'a> {
+    node: SortedRevTokensTrieNode<'a>,
+}
+
+impl<'a> Iterator for SortedRevTokensTrieNextStates<'a> {
+    type Item = (u8, SortedRevTokensTrieNode<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = &self.node;
+        let byte = node.byte_at(*node.ids.first()?)?;
+        let len = node
+            .ids
+            .partition_point(|&id| node.byte_at(id) <= Some(byte));
+        let (ids, rest) = node.ids.split_at(len);
+        let child = SortedRevTokensTrieNode {
+            vocab: node.vocab,
+            ids,
+            depth: node.depth + 1,
+        };
+        self.node.ids = rest;
+        Some((byte, child))
+    }
+}
+
+/// Returns the automaton along with token ids sorted by their reversed bytes.
+pub(crate) fn build_sam_of_reversed_tokens(
+    vocab: &TokenArena,
+) -> (GeneralSam<BoxBisectTable<u8>>, Vec<TokenId>) {
+    let mut ids: Vec<_> = (0..vocab.len() as TokenId).collect();
+    radix_sort_token_ids(&mut ids, |id| {
+        vocab
+            .get(id as usize)
+            .unwrap_or_default()
+            .iter()
+            .rev()
+            .copied()
+    });
+    let root = SortedRevTokensTrieNode {
+        vocab,
+        ids: &ids,
+        depth: 0,
+    };
+    let sam = GeneralSam::<SortedVecTransTable>::from_trie(root).alter_trans_table_into();
+    (sam, ids)
+}
+
+#[derive(Debug)]
+pub(crate) struct SortResult {
+    pub rank_ranges: Vec<SortedTokenRange>,
+    pub order: Vec<TokenId>,
+    pub rank: Vec<SortedTokenId>,
+}
+
+pub(crate) fn sort_vocab(vocab: &TokenArena) -> SortResult {
+    let vocab_size = vocab.len();
+    let token = |id: TokenId| vocab.get(id as usize).unwrap_or_default();
 
     let order = {
         let mut order: Vec<_> = (0..vocab_size as TokenId).collect();
-        order.sort_by_key(|&i| rank_ranges[i as usize].lower);
+        radix_sort_token_ids(&mut order, |id| token(id).iter().copied());
         order
     };
 
@@ -134,6 +252,43 @@ pub(crate) fn sort_vocab_with_trie<I: Ord + Clone, T: AsRef<[I]>, V: IntoIterato
         rank
     };
 
+    // `lower` of a duplicated token temporarily points to its first occurrence
+    let mut sorted_rank_ranges = vec![SortedTokenRange::default(); vocab_size];
+    let mut prefix_stack: Vec<usize> = Vec::new();
+    for (k, &id) in order.iter().enumerate() {
+        let cur = token(id);
+        while let Some(&top) = prefix_stack.last() {
+            if cur.starts_with(token(order[top])) {
+                break;
+            }
+            prefix_stack.pop();
+            sorted_rank_ranges[top].upper = k as SortedTokenId;
+        }
+        match prefix_stack.last() {
+            Some(&top) if token(order[top]) == cur => {
+                sorted_rank_ranges[k].lower = top as SortedTokenId;
+            }
+            _ => {
+                sorted_rank_ranges[k].lower = k as SortedTokenId;
+                prefix_stack.push(k);
+            }
+        }
+    }
+    for top in prefix_stack {
+        sorted_rank_ranges[top].upper = vocab_size as SortedTokenId;
+    }
+    for k in 0..vocab_size {
+        let first = sorted_rank_ranges[k].lower as usize;
+        if first != k {
+            sorted_rank_ranges[k].upper = sorted_rank_ranges[first].upper;
+        }
+    }
+
+    let rank_ranges: Vec<_> = rank
+        .iter()
+        .map(|&k| sorted_rank_ranges[k as usize].clone())
+        .collect();
+
     debug_assert_eq!(order.len(), vocab_size);
     debug_assert_eq!(rank.len(), vocab_size);
     debug_assert_eq!(rank_ranges.len(), vocab_size);
@@ -145,21 +300,34 @@ pub(crate) fn sort_vocab_with_trie<I: Ord + Clone, T: AsRef<[I]>, V: IntoIterato
     }
 }
 
-pub(crate) fn label_rank_range_on_sam_of_rev_tokens<
-    K: Ord + Clone,
-    T: AsRef<[K]>,
-    V: IntoIterator<Item = (T, SortedTokenRange)>,
-    TransTable: TransitionTable<KeyType = K>,
->(
+pub(crate) fn label_rank_range_on_sam_of_rev_tokens<TransTable: TransitionTable<KeyType = u8>>(
     sam_of_rev_tokens: &GeneralSam<TransTable>,
-    vocab_and_rank_ranges: V,
+    vocab: &TokenArena,
+    rev_order: &[TokenId],
+    token_rank_ranges: &[SortedTokenRange],
 ) -> Vec<Option<SortedTokenRange>> {
     let mut rank_ranges = vec![None; sam_of_rev_tokens.num_of_nodes()];
 
-    for (token, rank_range) in vocab_and_rank_ranges {
-        let mut state = sam_of_rev_tokens.get_root_state();
-        state.feed_ref(token.as_ref().iter().rev());
-        rank_ranges[state.node_id] = Some(rank_range);
+    // walking tokens in reversed order, only bytes after the common suffix
+    // with the previous token need to be fed
+    let mut states = vec![SAM_ROOT_NODE_ID];
+    let mut prev_token: &[u8] = &[];
+    for &id in rev_order {
+        let token = vocab.get(id as usize).unwrap_or_default();
+        let common_len = token
+            .iter()
+            .rev()
+            .zip(prev_token.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        states.truncate(common_len + 1);
+        for byte in token.iter().rev().skip(common_len) {
+            let mut state = sam_of_rev_tokens.get_state(states[states.len() - 1]);
+            state.goto(byte);
+            states.push(state.node_id);
+        }
+        rank_ranges[states[token.len()]] = Some(token_rank_ranges[id as usize].clone());
+        prev_token = token;
     }
 
     for &id in sam_of_rev_tokens
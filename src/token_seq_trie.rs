@@ -0,0 +1,745 @@
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+
+use general_sam::{BTreeTransTable, TravelEvent, Trie, TrieNodeAlike};
+
+use crate::TokenId;
+
+/// A node of a [`TokenSeqTrie`], where roots are their own parents
+/// and `subtree_upper` is inclusive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenSeqTrieNode<V> {
+    pub parent: usize,
+    pub subtree_lower: usize,
+    pub subtree_upper: usize,
+    pub depth: usize,
+    pub num_children: usize,
+    pub token: TokenId,
+    pub value: Option<V>,
+}
+
+/// A maximal chain of nodes `lower..upper` in DFS order
+/// where only the last node may branch or end inputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct TokenSeqSegment {
+    /// The segment containing the parent of `lower`, itself for roots.
+    pub parent: usize,
+    pub lower: usize,
+    pub upper: usize,
+    /// Indices of inputs passing through the segment, in ascending order.
+    pub inputs: Vec<usize>,
+}
+
+/// Nodes `lower..upper` in DFS order prefilled in one step,
+/// attending to the KV of `visible_chunks` computed earlier.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct TokenSeqChunk {
+    pub lower: usize,
+    pub upper: usize,
+    /// Earlier chunks holding ancestors of the nodes, in ascending order.
+    pub visible_chunks: Vec<usize>,
+}
+
+/// Which value a node keeps when several inputs end at it,
+/// where inputs without values never replace one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TokenSeqValuePolicy {
+    First,
+    #[default]
+    Last,
+}
+
+/// The order in which siblings are visited during DFS linearisation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum TokenSeqChildOrder {
+    /// Ascending token ids.
+    #[default]
+    TokenId,
+    /// The first input passing through each child.
+    Insertion,
+    /// The lowest priority of inputs passing through each child,
+    /// where inputs without a priority come last and ties keep token order.
+    Priority(Vec<i64>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenSeqTrieOptions {
+    pub value_policy: TokenSeqValuePolicy,
+    pub child_order: TokenSeqChildOrder,
+}
+
+/// The trie of token sequences with nodes in DFS order,
+/// so that every subtree is a contiguous range of nodes.
+#[derive(Clone, Debug)]
+pub struct TokenSeqTrie<V> {
+    nodes: Vec<TokenSeqTrieNode<V>>,
+    input_nodes: Vec<Option<usize>>,
+    // inputs ending at node `i` are inputs[input_offsets[i]..input_offsets[i + 1]]
+    input_offsets: Vec<usize>,
+    inputs: Vec<usize>,
+}
+
+impl<V> Default for TokenSeqTrie<V> {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            input_nodes: Vec::new(),
+            input_offsets: vec![0],
+            inputs: Vec::new(),
+        }
+    }
+}
+
+impl<V> TokenSeqTrie<V> {
+    /// Builds the trie, attaching each value to the last node of its sequence.
+    pub fn new<S: AsRef<[TokenId]>, I: IntoIterator<Item = (S, Option<V>)>>(inputs: I) -> Self {
+        Self::with_policy(inputs, TokenSeqValuePolicy::default())
+    }
+
+    pub fn with_policy<S: AsRef<[TokenId]>, I: IntoIterator<Item = (S, Option<V>)>>(
+        inputs: I,
+        policy: TokenSeqValuePolicy,
+    ) -> Self {
+        let options = TokenSeqTrieOptions {
+            value_policy: policy,
+            ..Default::default()
+        };
+        Self::with_options(inputs, &options)
+    }
+
+    pub fn with_options<S: AsRef<[TokenId]>, I: IntoIterator<Item = (S, Option<V>)>>(
+        inputs: I,
+        options: &TokenSeqTrieOptions,
+    ) -> Self {
+        let policy = options.value_policy;
+        let mut trie = Trie::<BTreeTransTable<_>>::default();
+        let mut values = Vec::new();
+        for (seq, value) in inputs {
+            values.push((trie.insert(seq.as_ref().iter().copied()), value));
+        }
+
+        let mut nodes: Vec<TokenSeqTrieNode<V>> = Vec::new();
+        let mut rank = vec![None; trie.num_of_nodes()];
+        let res = trie
+            .get_root_state()
+            .dfs_travel(|event| -> Result<(), Infallible> {
+                match event {
+                    TravelEvent::PushRoot(_) => {}
+                    TravelEvent::Push(node, _, token) => {
+                        let dfs_order_id = nodes.len();
+                        rank[node.node_id] = Some(dfs_order_id);
+                        let parent = node
+                            .get_node()
+                            .and_then(|n| rank[n.get_parent()])
+                            .unwrap_or(dfs_order_id);
+                        nodes.push(TokenSeqTrieNode {
+                            parent,
+                            subtree_lower: dfs_order_id,
+                            subtree_upper: dfs_order_id,
+                            depth: 0,
+                            num_children: 0,
+                            token,
+                            value: None,
+                        });
+                    }
+                    TravelEvent::Pop(node, _) => {
+                        if let Some(id) = rank[node.node_id]
+                            && let Some(parent) = node.get_node().and_then(|n| rank[n.get_parent()])
+                        {
+                            nodes[parent].subtree_upper = nodes[id].subtree_upper;
+                            nodes[parent].num_children += 1;
+                        }
+                    }
+                }
+                Ok(())
+            });
+        match res {
+            Ok(()) => {}
+            Err(e) => match e {},
+        }
+
+        for i in 0..nodes.len() {
+            let parent = nodes[i].parent;
+            if parent == i {
+                continue;
+            }
+            nodes[i].depth = nodes[parent].depth + 1;
+        }
+
+        // empty sequences end at no node
+        let mut input_nodes: Vec<_> = values.iter().map(|&(node_id, _)| rank[node_id]).collect();
+        for (&id, (_, value)) in input_nodes.iter().zip(values) {
+            let Some(node) = id.map(|id| &mut nodes[id]) else {
+                continue;
+            };
+            if value.is_some() && (node.value.is_none() || policy == TokenSeqValuePolicy::Last) {
+                node.value = value;
+            }
+        }
+
+        let priority = |input: usize| match &options.child_order {
+            TokenSeqChildOrder::TokenId => None,
+            TokenSeqChildOrder::Insertion => Some(input as i64),
+            TokenSeqChildOrder::Priority(priorities) => {
+                Some(priorities.get(input).copied().unwrap_or(i64::MAX))
+            }
+        };
+        if options.child_order != TokenSeqChildOrder::TokenId {
+            let mut keys = vec![i64::MAX; nodes.len()];
+            for (input, &id) in input_nodes.iter().enumerate() {
+                if let (Some(id), Some(key)) = (id, priority(input)) {
+                    keys[id] = keys[id].min(key);
+                }
+            }
+            let new_ids = reorder(&mut nodes, &keys);
+            input_nodes
+                .iter_mut()
+                .flatten()
+                .for_each(|id| *id = new_ids[*id]);
+        }
+
+        let mut input_offsets = vec![0; nodes.len() + 1];
+        input_nodes
+            .iter()
+            .flatten()
+            .for_each(|&id| input_offsets[id + 1] += 1);
+        for i in 0..nodes.len() {
+            input_offsets[i + 1] += input_offsets[i];
+        }
+        let mut cursor = input_offsets.clone();
+        let mut inputs = vec![0; input_offsets[nodes.len()]];
+        for (i, &id) in input_nodes.iter().enumerate() {
+            if let Some(id) = id {
+                inputs[cursor[id]] = i;
+                cursor[id] += 1;
+            }
+        }
+
+        #[cfg(debug_assertions)]
+        for (i, node) in nodes.iter().enumerate() {
+            debug_assert!(node.subtree_lower <= node.subtree_upper);
+            debug_assert!(node.subtree_lower == i);
+            debug_assert!(node.parent <= i);
+            if node.parent < node.subtree_lower {
+                let parent = &nodes[node.parent];
+                debug_assert!(parent.subtree_lower < node.subtree_lower);
+                debug_assert!(parent.subtree_upper >= node.subtree_lower);
+            } else {
+                debug_assert!(node.parent == i);
+            }
+        }
+
+        Self {
+            nodes,
+            input_nodes,
+            input_offsets,
+            inputs,
+        }
+    }
+
+    pub fn nodes(&self) -> &[TokenSeqTrieNode<V>] {
+        &self.nodes
+    }
+
+    pub fn into_nodes(self) -> Vec<TokenSeqTrieNode<V>> {
+        self.nodes
+    }
+
+    /// The node each input ends at, `None` for empty sequences.
+    pub fn input_nodes(&self) -> &[Option<usize>] {
+        &self.input_nodes
+    }
+
+    /// Indices of inputs ending at a node, in ascending order.
+    pub fn inputs_of(&self, node: usize) -> &[usize] {
+        match self.input_offsets.get(node..node + 2) {
+            Some(&[lower, upper]) => &self.inputs[lower..upper],
+            _ => &[],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Row-major `n * n` mask where row `i` marks the ancestors-or-self of node `i`.
+    pub fn ancestor_mask(&self) -> Vec<bool> {
+        ancestor_mask_with(self.len(), |i| self.nodes[i].parent).expect("parents precede children")
+    }
+
+    /// [`TokenSeqTrie::ancestor_mask`] with each row packed into `n.div_ceil(8)` bytes,
+    /// where node `j` is bit `j % 8` of byte `j / 8`.
+    pub fn packed_ancestor_mask(&self) -> Vec<u8> {
+        packed_ancestor_mask_with(self.len(), |i| self.nodes[i].parent)
+            .expect("parents precede children")
+    }
+
+    /// Depths of nodes offset by the length of a prefix shared by all sequences.
+    pub fn position_ids(&self, prefix_len: usize) -> Vec<usize> {
+        self.nodes
+            .iter()
+            .map(|node| node.depth + prefix_len)
+            .collect()
+    }
+
+    /// The radix-compressed view of the trie with segments in DFS order.
+    pub fn segments(&self) -> Vec<TokenSeqSegment> {
+        let (parents, num_children): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter()
+            .map(|node| (node.parent, node.num_children))
+            .unzip();
+        segments(&parents, &num_children, &self.input_nodes).expect("parents precede children")
+    }
+
+    /// Number of inputs ending within the subtree of each node.
+    pub fn subtree_input_counts(&self) -> Vec<usize> {
+        let counts = (0..self.len())
+            .map(|i| Some(self.inputs_of(i).len()))
+            .collect();
+        reduce_subtrees_with(|i| self.nodes[i].parent, counts, |a, b| a + b)
+            .expect("parents precede children")
+            .into_iter()
+            .map(Option::unwrap_or_default)
+            .collect()
+    }
+
+    /// Reduces mapped values within the subtree of each node,
+    /// `None` for subtrees without values.
+    pub fn reduce_subtree_values<T: Clone>(
+        &self,
+        mut map: impl FnMut(&V) -> T,
+        reduce: impl FnMut(T, T) -> T,
+    ) -> Vec<Option<T>> {
+        let values = self
+            .nodes
+            .iter()
+            .map(|n| n.value.as_ref().map(&mut map))
+            .collect();
+        reduce_subtrees_with(|i| self.nodes[i].parent, values, reduce)
+            .expect("parents precede children")
+    }
+
+    /// Splits nodes in DFS order into chunks of at most `max_tokens` nodes,
+    /// or `None` for `max_tokens == 0`.
+    pub fn prefill_chunks(&self, max_tokens: usize) -> Option<Vec<TokenSeqChunk>> {
+        (max_tokens > 0).then(|| {
+            prefill_chunks_with(self.len(), max_tokens, |i| self.nodes[i].parent)
+                .expect("parents precede children")
+        })
+    }
+
+    pub fn ancestor_index(&self) -> AncestorIndex {
+        let (depths, parents) = self.nodes.iter().map(|n| (n.depth, n.parent)).unzip();
+        AncestorIndex::with_depths(depths, parents)
+    }
+
+    /// Tokens from the root to the node.
+    pub fn token_path(&self, node: usize) -> Option<Vec<TokenId>> {
+        self.nodes.get(node)?;
+        let path = token_path_with(node, |i| self.nodes[i].parent, |i| self.nodes[i].token);
+        Some(path.expect("parents precede children"))
+    }
+
+    /// Number of leading nodes forming a chain without values,
+    /// which every sequence shares and can be prefilled once.
+    pub fn prefill_chain_len(&self) -> usize {
+        self.nodes
+            .iter()
+            .take_while(|node| node.num_children == 1 && node.value.is_none())
+            .count()
+    }
+}
+
+/// Re-linearises nodes in DFS order, visiting siblings by ascending keys of their subtrees,
+/// and returns the new id of each node.
+fn reorder<V>(nodes: &mut Vec<TokenSeqTrieNode<V>>, keys: &[i64]) -> Vec<usize> {
+    let mut keys = keys.to_vec();
+    for i in (0..nodes.len()).rev() {
+        let parent = nodes[i].parent;
+        if parent != i {
+            keys[parent] = keys[parent].min(keys[i]);
+        }
+    }
+
+    let mut roots = Vec::new();
+    let mut children = vec![Vec::new(); nodes.len()];
+    for (i, node) in nodes.iter().enumerate() {
+        match node.parent {
+            parent if parent == i => roots.push(i),
+            parent => children[parent].push(i),
+        }
+    }
+    // stable sorts keep token order among equal keys
+    roots.sort_by_key(|&i| keys[i]);
+    children
+        .iter_mut()
+        .for_each(|c| c.sort_by_key(|&i| keys[i]));
+
+    let mut order = Vec::with_capacity(nodes.len());
+    let mut stack: Vec<_> = roots.into_iter().rev().collect();
+    while let Some(i) = stack.pop() {
+        order.push(i);
+        stack.extend(children[i].iter().rev());
+    }
+
+    let mut new_ids = vec![0; nodes.len()];
+    for (new_id, &old_id) in order.iter().enumerate() {
+        new_ids[old_id] = new_id;
+    }
+    let mut old_nodes: Vec<_> = std::mem::take(nodes).into_iter().map(Some).collect();
+    for (new_id, &old_id) in order.iter().enumerate() {
+        let mut node = old_nodes[old_id].take().expect("each node is visited once");
+        node.parent = new_ids[node.parent];
+        node.subtree_upper = new_id + (node.subtree_upper - node.subtree_lower);
+        node.subtree_lower = new_id;
+        nodes.push(node);
+    }
+    new_ids
+}
+
+/// Binary lifting over nodes in DFS order for ancestor queries.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(frozen, skip_from_py_object))]
+pub struct AncestorIndex {
+    depths: Vec<usize>,
+    // jumps[k][i] is the 2^k-th ancestor of node `i`, or its root if there is none
+    jumps: Vec<Vec<usize>>,
+}
+
+impl AncestorIndex {
+    /// Returns `None` if some parent does not precede its child.
+    pub fn new(parents: &[usize]) -> Option<Self> {
+        let mut depths = Vec::with_capacity(parents.len());
+        for (i, &parent) in parents.iter().enumerate() {
+            match parent.cmp(&i) {
+                std::cmp::Ordering::Less => depths.push(depths[parent] + 1),
+                std::cmp::Ordering::Equal => depths.push(0),
+                std::cmp::Ordering::Greater => return None,
+            }
+        }
+        Some(Self::with_depths(depths, parents.to_vec()))
+    }
+
+    fn with_depths(depths: Vec<usize>, parents: Vec<usize>) -> Self {
+        let max_depth = depths.iter().copied().max().unwrap_or_default();
+        let mut jumps = vec![parents];
+        while 1 << jumps.len() <= max_depth {
+            let last = jumps.last().expect("jumps are not empty");
+            jumps.push(last.iter().map(|&j| last[j]).collect());
+        }
+        Self { depths, jumps }
+    }
+
+    pub fn len(&self) -> usize {
+        self.depths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depths.is_empty()
+    }
+
+    pub fn depth(&self, node: usize) -> Option<usize> {
+        self.depths.get(node).copied()
+    }
+
+    /// The ancestor `k` levels above the node, the node itself for `k == 0`.
+    pub fn kth_ancestor(&self, node: usize, k: usize) -> Option<usize> {
+        if k > self.depth(node)? {
+            return None;
+        }
+        Some(self.lift(node, k))
+    }
+
+    /// The lowest common ancestor, or `None` if the nodes are in different trees.
+    pub fn lca(&self, a: usize, b: usize) -> Option<usize> {
+        let (depth_a, depth_b) = (self.depth(a)?, self.depth(b)?);
+        let (mut a, mut b) = if depth_a < depth_b {
+            (a, self.lift(b, depth_b - depth_a))
+        } else {
+            (self.lift(a, depth_a - depth_b), b)
+        };
+        if a == b {
+            return Some(a);
+        }
+        for level in self.jumps.iter().rev() {
+            if level[a] != level[b] {
+                (a, b) = (level[a], level[b]);
+            }
+        }
+        let parent = self.jumps[0][a];
+        (parent == self.jumps[0][b]).then_some(parent)
+    }
+
+    fn lift(&self, mut node: usize, k: usize) -> usize {
+        for (bit, level) in self.jumps.iter().enumerate() {
+            if k >> bit & 1 == 1 {
+                node = level[node];
+            }
+        }
+        node
+    }
+}
+
+/// Reduces values within the subtree of each node of DFS order in one pass,
+/// combining them in DFS order so that `reduce` only has to be associative.
+///
+/// Returns `None` if lengths differ or some parent does not precede its child.
+pub fn reduce_subtrees<T: Clone>(
+    parents: &[usize],
+    values: Vec<Option<T>>,
+    reduce: impl FnMut(T, T) -> T,
+) -> Option<Vec<Option<T>>> {
+    if values.len() != parents.len() {
+        return None;
+    }
+    reduce_subtrees_with(|i| parents[i], values, reduce)
+}
+
+/// [`reduce_subtrees`] over `values.len()` nodes.
+fn reduce_subtrees_with<T: Clone>(
+    parent_of: impl Fn(usize) -> usize,
+    values: Vec<Option<T>>,
+    mut reduce: impl FnMut(T, T) -> T,
+) -> Option<Vec<Option<T>>> {
+    let n = values.len();
+    if (0..n).any(|i| parent_of(i) > i) {
+        return None;
+    }
+    let mut combine = |a: Option<T>, b: Option<T>| match (a, b) {
+        (Some(a), Some(b)) => Some(reduce(a, b)),
+        (a, b) => a.or(b),
+    };
+    // reductions of the children visited so far, which come later in DFS order
+    let mut children: Vec<Option<T>> = vec![None; n];
+    let mut res = values;
+    for i in (0..n).rev() {
+        res[i] = combine(res[i].take(), children[i].take());
+        let parent = parent_of(i);
+        if parent != i {
+            children[parent] = combine(res[i].clone(), children[parent].take());
+        }
+    }
+    Some(res)
+}
+
+/// Tokens from the root to the node, or `None` if the node is out of range,
+/// lengths differ, or some parent does not precede its child.
+pub fn token_path(parents: &[usize], tokens: &[TokenId], node: usize) -> Option<Vec<TokenId>> {
+    if node >= parents.len() || tokens.len() != parents.len() {
+        return None;
+    }
+    token_path_with(node, |i| parents[i], |i| tokens[i])
+}
+
+fn token_path_with(
+    node: usize,
+    parent_of: impl Fn(usize) -> usize,
+    token_of: impl Fn(usize) -> TokenId,
+) -> Option<Vec<TokenId>> {
+    let mut path = vec![token_of(node)];
+    let mut current = node;
+    loop {
+        let parent = parent_of(current);
+        if parent == current {
+            break;
+        }
+        if parent > current {
+            return None;
+        }
+        current = parent;
+        path.push(token_of(current));
+    }
+    path.reverse();
+    Some(path)
+}
+
+/// The radix-compressed view of nodes in DFS order, merging each node into its parent
+/// if the parent has a single child and no input ends there.
+///
+/// Returns `None` if lengths differ, some parent does not precede its child,
+/// or some input ends out of range.
+pub fn segments(
+    parents: &[usize],
+    num_children: &[usize],
+    input_nodes: &[Option<usize>],
+) -> Option<Vec<TokenSeqSegment>> {
+    let n = parents.len();
+    if num_children.len() != n || parents.iter().enumerate().any(|(i, &p)| p > i) {
+        return None;
+    }
+    let mut has_inputs = vec![false; n];
+    for &node in input_nodes.iter().flatten() {
+        *has_inputs.get_mut(node)? = true;
+    }
+
+    let mut segments: Vec<TokenSeqSegment> = Vec::new();
+    let mut segment_of: Vec<usize> = Vec::with_capacity(n);
+    for (i, &parent) in parents.iter().enumerate() {
+        let extends = parent != i && num_children[parent] == 1 && !has_inputs[parent];
+        if extends {
+            let id = segment_of[parent];
+            segments[id].upper = i + 1;
+            segment_of.push(id);
+        } else {
+            let id = segments.len();
+            segments.push(TokenSeqSegment {
+                parent: if parent == i { id } else { segment_of[parent] },
+                lower: i,
+                upper: i + 1,
+                inputs: Vec::new(),
+            });
+            segment_of.push(id);
+        }
+    }
+
+    for (input, &node) in input_nodes.iter().enumerate() {
+        let Some(node) = node else {
+            continue;
+        };
+        let mut id = segment_of[node];
+        loop {
+            segments[id].inputs.push(input);
+            if segments[id].parent == id {
+                break;
+            }
+            id = segments[id].parent;
+        }
+    }
+    Some(segments)
+}
+
+/// Splits nodes in DFS order into chunks of at most `max_tokens` nodes,
+/// or `None` for `max_tokens == 0` or if some parent does not precede its child.
+///
+/// Since parents precede children, every node comes after its ancestors.
+pub fn prefill_chunks(parents: &[usize], max_tokens: usize) -> Option<Vec<TokenSeqChunk>> {
+    if max_tokens == 0 {
+        return None;
+    }
+    prefill_chunks_with(parents.len(), max_tokens, |i| parents[i])
+}
+
+fn prefill_chunks_with(
+    n: usize,
+    max_tokens: usize,
+    parent_of: impl Fn(usize) -> usize,
+) -> Option<Vec<TokenSeqChunk>> {
+    let mut chunks = Vec::with_capacity(n.div_ceil(max_tokens));
+    // marks[j] is the last chunk visiting node `j` while walking up
+    let mut marks = vec![usize::MAX; n];
+    for (id, lower) in (0..n).step_by(max_tokens).enumerate() {
+        let upper = (lower + max_tokens).min(n);
+        let mut visible = BTreeSet::new();
+        for i in lower..upper {
+            let mut j = i;
+            loop {
+                let parent = parent_of(j);
+                if parent > j {
+                    return None;
+                }
+                // ancestors within the chunk or marked have been walked up from already
+                if parent == j || parent >= lower || marks[parent] == id {
+                    break;
+                }
+                j = parent;
+                marks[j] = id;
+                visible.insert(j / max_tokens);
+            }
+        }
+        chunks.push(TokenSeqChunk {
+            lower,
+            upper,
+            visible_chunks: visible.into_iter().collect(),
+        });
+    }
+    Some(chunks)
+}
+
+/// Calls `set(i, j)` for every ancestor-or-self `j` of node `i` among `n` nodes,
+/// returning `false` if some parent does not precede its child.
+fn for_each_ancestor(
+    n: usize,
+    parent_of: impl Fn(usize) -> usize,
+    mut set: impl FnMut(usize, usize),
+) -> bool {
+    for i in 0..n {
+        let mut j = i;
+        loop {
+            set(i, j);
+            let parent = parent_of(j);
+            if parent == j {
+                break;
+            }
+            if parent > j {
+                return false;
+            }
+            j = parent;
+        }
+    }
+    true
+}
+
+fn ancestor_mask_with<T: Copy + Default + From<bool>>(
+    n: usize,
+    parent_of: impl Fn(usize) -> usize,
+) -> Option<Vec<T>> {
+    let mut mask = vec![T::default(); n * n];
+    for_each_ancestor(n, parent_of, |i, j| mask[i * n + j] = true.into()).then_some(mask)
+}
+
+fn packed_ancestor_mask_with(n: usize, parent_of: impl Fn(usize) -> usize) -> Option<Vec<u8>> {
+    let stride = n.div_ceil(8);
+    let mut mask = vec![0u8; n * stride];
+    for_each_ancestor(n, parent_of, |i, j| {
+        mask[i * stride + j / 8] |= 1 << (j % 8)
+    })
+    .then_some(mask)
+}
+
+/// Row-major `n * n` ancestor-or-self mask of nodes in DFS order,
+/// as `bool`s or `0`/`1` bytes,
+/// or `None` if some parent does not precede its child.
+pub fn ancestor_mask<T: Copy + Default + From<bool>>(parents: &[usize]) -> Option<Vec<T>> {
+    ancestor_mask_with(parents.len(), |i| parents[i])
+}
+
+/// [`ancestor_mask`] with each row packed into `n.div_ceil(8)` bytes in little bit order.
+pub fn packed_ancestor_mask(parents: &[usize]) -> Option<Vec<u8>> {
+    packed_ancestor_mask_with(parents.len(), |i| parents[i])
+}
+
+#[cfg(feature = "pyo3")]
+mod _pyo3 {
+    use pyo3::pymethods;
+
+    use super::AncestorIndex;
+
+    #[pymethods]
+    impl AncestorIndex {
+        fn __len__(&self) -> usize {
+            self.len()
+        }
+
+        #[pyo3(name = "depth")]
+        fn depth_py(&self, node: usize) -> Option<usize> {
+            self.depth(node)
+        }
+
+        #[pyo3(name = "kth_ancestor")]
+        fn kth_ancestor_py(&self, node: usize, k: usize) -> Option<usize> {
+            self.kth_ancestor(node, k)
+        }
+
+        #[pyo3(name = "lca")]
+        fn lca_py(&self, a: usize, b: usize) -> Option<usize> {
+            self.lca(a, b)
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use general_sam::{GeneralSam, GeneralSamNodeID, SAM_NIL_NODE_ID, TransitionTable};
+
+const BLOCK_BITS: usize = u64::BITS as usize;
+
+/// Dense 256-entry transition rows for high-degree states of an automaton,
+/// so that stepping from them takes no binary search.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DenseTransitions {
+    is_dense: Vec<u64>,
+    num_dense_before_block: Vec<u32>,
+    rows: Vec<[u32; 256]>,
+}
+
+impl DenseTransitions {
+    /// Gives every state with at least `threshold` transitions a dense row.
+    pub fn new<TransTable: TransitionTable<KeyType = u8>>(
+        sam: &GeneralSam<TransTable>,
+        threshold: usize,
+    ) -> Self {
+        let num_of_nodes = sam.num_of_nodes();
+        if threshold > 256 || u32::try_from(num_of_nodes).is_err() {
+            return Self::default();
+        }
+
+        let mut is_dense = vec![0u64; num_of_nodes.div_ceil(BLOCK_BITS)];
+        let mut rows = Vec::new();
+        for id in 0..num_of_nodes {
+            let Some(node) = sam.get_node(id) else {
+                continue;
+            };
+            let trans = node.get_trans();
+            if trans.iter().count() < threshold.max(1) {
+                continue;
+            }
+            let mut row = [SAM_NIL_NODE_ID as u32; 256];
+            trans
+                .iter()
+                .for_each(|(byte, &next)| row[byte as usize] = next as u32);
+            rows.push(row);
+            is_dense[id / BLOCK_BITS] |= 1 << (id % BLOCK_BITS);
+        }
+        rows.shrink_to_fit();
+
+        let num_dense_before_block = is_dense
+            .iter()
+            .scan(0, |cnt, block| {
+                let before = *cnt;
+                *cnt += block.count_ones();
+                Some(before)
+            })
+            .collect();
+
+        Self {
+            is_dense,
+            num_dense_before_block,
+            rows,
+        }
+    }
+
+    pub fn num_of_rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    #[inline]
+    pub fn row(&self, node_id: GeneralSamNodeID) -> Option<&[u32; 256]> {
+        let (block, bit) = (node_id / BLOCK_BITS, node_id % BLOCK_BITS);
+        let bits = *self.is_dense.get(block)?;
+        if bits & (1 << bit) == 0 {
+            return None;
+        }
+        let below = (bits & ((1 << bit) - 1)).count_ones();
+        let k = self.num_dense_before_block[block] + below;
+        Some(&self.rows[k as usize])
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.is_dense.capacity() * size_of::<u64>()
+            + self.num_dense_before_block.capacity() * size_of::<u32>()
+            + self.rows.capacity() * size_of::<[u32; 256]>()
+    }
+}
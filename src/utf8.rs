@@ -0,0 +1,258 @@
+use crate::{PrefixIndex, SortedTokenId, SortedTokenRange};
+
+/// Whether a character can start at `byte`, i.e. it is not a continuation byte.
+#[inline]
+pub fn is_utf8_char_boundary(byte: u8) -> bool {
+    (byte as i8) >= -0x40
+}
+
+/// Splits bytes into the longest valid UTF-8 prefix and the pending rest,
+/// such as a character cut in half at the end.
+pub fn split_utf8(bytes: &[u8]) -> (&str, &[u8]) {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => (s, &[]),
+        Err(e) => {
+            let (valid, pending) = bytes.split_at(e.valid_up_to());
+            (std::str::from_utf8(valid).unwrap_or_default(), pending)
+        }
+    }
+}
+
+/// How a token cuts through UTF-8 characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Utf8Class {
+    /// Starts with a continuation byte.
+    pub starts_mid_char: bool,
+    /// Ends with a character missing some of its bytes.
+    pub ends_mid_char: bool,
+    /// Number of bytes missing from the last character.
+    pub num_pending_bytes: u8,
+    pub is_ascii: bool,
+}
+
+impl Utf8Class {
+    pub fn of(token: &[u8]) -> Self {
+        let num_pending_bytes = token
+            .iter()
+            .rev()
+            .take(4)
+            .position(|&b| is_utf8_char_boundary(b))
+            .map_or(0, |k| {
+                let width: usize = match token[token.len() - 1 - k] {
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    0xf0..=0xf7 => 4,
+                    _ => 1,
+                };
+                width.saturating_sub(k + 1) as u8
+            });
+        Self {
+            starts_mid_char: token.first().is_some_and(|&b| !is_utf8_char_boundary(b)),
+            ends_mid_char: num_pending_bytes > 0,
+            num_pending_bytes,
+            is_ascii: token.is_ascii(),
+        }
+    }
+}
+
+/// [`Utf8Class`] of tokens as columns.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(get_all, frozen, skip_from_py_object))]
+pub struct Utf8ClassColumns {
+    pub starts_mid_char: Vec<bool>,
+    pub ends_mid_char: Vec<bool>,
+    pub num_pending_bytes: Vec<u8>,
+    pub is_ascii: Vec<bool>,
+}
+
+impl FromIterator<Utf8Class> for Utf8ClassColumns {
+    fn from_iter<I: IntoIterator<Item = Utf8Class>>(iter: I) -> Self {
+        let mut res = Self::default();
+        for class in iter {
+            res.starts_mid_char.push(class.starts_mid_char);
+            res.ends_mid_char.push(class.ends_mid_char);
+            res.num_pending_bytes.push(class.num_pending_bytes);
+            res.is_ascii.push(class.is_ascii);
+        }
+        res
+    }
+}
+
+#[cfg(feature = "pyo3")]
+mod _pyo3 {
+    use pyo3::pymethods;
+
+    use pyo3::exceptions::PyValueError;
+    use pyo3::{PyResult, Python};
+
+    use super::{Utf8ClassColumns, Utf8Constraint};
+    use crate::SortedTokenRange;
+
+    #[pymethods]
+    impl Utf8ClassColumns {
+        fn __len__(&self) -> usize {
+            self.is_ascii.len()
+        }
+    }
+
+    #[pymethods]
+    impl Utf8Constraint {
+        #[pyo3(name = "allowed")]
+        #[pyo3(signature=(pending, within=None))]
+        fn allowed_py(
+            &self,
+            py: Python<'_>,
+            pending: &[u8],
+            within: Option<&SortedTokenRange>,
+        ) -> PyResult<Vec<SortedTokenRange>> {
+            py.detach(|| match within {
+                Some(range) => self.allowed_within(pending, range),
+                None => self.allowed(pending),
+            })
+            .ok_or_else(|| PyValueError::new_err("pending bytes can not start a character"))
+        }
+
+        #[pyo3(name = "heap_size")]
+        fn heap_size_py(&self) -> usize {
+            self.heap_size()
+        }
+    }
+}
+
+/// Tokens that keep generated bytes valid UTF-8, as ranges of sorted tokens.
+///
+/// Tokens completing a character pending from earlier bytes start with
+/// a known number of continuation bytes, and tokens starting with bytes
+/// within some bounds are contiguous in the sorted vocab.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "pyo3", pyo3::pyclass(frozen, skip_from_py_object))]
+pub struct Utf8Constraint {
+    // ranges_by_num_needed[n] for tokens starting with `n` continuation bytes
+    ranges_by_num_needed: [Vec<SortedTokenRange>; 4],
+    // sorted tokens starting with byte `b` are in [lowers[b], lowers[b + 1])
+    first_byte_lowers: Vec<SortedTokenId>,
+}
+
+impl Utf8Constraint {
+    pub fn new<P: PrefixIndex>(index: &P) -> Self {
+        let vocab = index.vocab();
+        let order = index.order();
+
+        let mut res = Self {
+            first_byte_lowers: vec![0; 257],
+            ..Default::default()
+        };
+        for (k, &id) in order.iter().enumerate() {
+            let token = vocab.get(id as usize).unwrap_or_default();
+            if let Some(&first) = token.first() {
+                res.first_byte_lowers[first as usize + 1] = k as SortedTokenId + 1;
+            } else {
+                res.first_byte_lowers[0] = k as SortedTokenId + 1;
+            }
+            for (num_needed, ranges) in res.ranges_by_num_needed.iter_mut().enumerate() {
+                if !completes_pending(token, num_needed) {
+                    continue;
+                }
+                let k = k as SortedTokenId;
+                match ranges.last_mut() {
+                    Some(last) if last.upper == k => last.upper = k + 1,
+                    _ => ranges.push(SortedTokenRange {
+                        lower: k,
+                        upper: k + 1,
+                    }),
+                }
+            }
+        }
+        for b in 0..256 {
+            res.first_byte_lowers[b + 1] =
+                res.first_byte_lowers[b + 1].max(res.first_byte_lowers[b]);
+        }
+        res
+    }
+
+    /// Ranges of sorted tokens allowed after bytes ending with `pending`,
+    /// the incomplete last character, `None` if `pending` can not start one.
+    pub fn allowed(&self, pending: &[u8]) -> Option<Vec<SortedTokenRange>> {
+        let vocab_size = self.first_byte_lowers.last().copied().unwrap_or(0);
+        self.allowed_within(
+            pending,
+            &SortedTokenRange {
+                lower: 0,
+                upper: vocab_size,
+            },
+        )
+    }
+
+    /// Like [`Utf8Constraint::allowed`], only keeping tokens in `range`,
+    /// e.g. the candidates of token healing.
+    pub fn allowed_within(
+        &self,
+        pending: &[u8],
+        range: &SortedTokenRange,
+    ) -> Option<Vec<SortedTokenRange>> {
+        let (num_needed, (lo, hi)) = next_byte_bounds(pending)?;
+        let lower = range.lower.max(self.first_byte_lowers[lo as usize]);
+        let upper = range.upper.min(self.first_byte_lowers[hi as usize + 1]);
+        let ranges = &self.ranges_by_num_needed[num_needed];
+        let start = ranges.partition_point(|r| r.upper <= lower);
+        Some(
+            ranges[start..]
+                .iter()
+                .take_while(|r| r.lower < upper)
+                .map(|r| SortedTokenRange {
+                    lower: r.lower.max(lower),
+                    upper: r.upper.min(upper),
+                })
+                .collect(),
+        )
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.ranges_by_num_needed
+            .iter()
+            .map(|r| r.capacity() * size_of::<SortedTokenRange>())
+            .sum::<usize>()
+            + self.first_byte_lowers.capacity() * size_of::<SortedTokenId>()
+    }
+}
+
+/// Whether `token` starts with `num_needed` continuation bytes
+/// (or is made of fewer of them), followed by valid UTF-8
+/// possibly cut at the end.
+fn completes_pending(token: &[u8], num_needed: usize) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    let (head, rest) = token.split_at(num_needed.min(token.len()));
+    if head.iter().any(|&b| is_utf8_char_boundary(b)) {
+        return false;
+    }
+    match std::str::from_utf8(rest) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Number of continuation bytes `pending` needs, and the bounds of the next one.
+fn next_byte_bounds(pending: &[u8]) -> Option<(usize, (u8, u8))> {
+    let Some((&lead, rest)) = pending.split_first() else {
+        return Some((0, (0x00, 0xff)));
+    };
+    match std::str::from_utf8(pending) {
+        Err(e) if e.valid_up_to() == 0 && e.error_len().is_none() => {}
+        _ => return None,
+    }
+    let width = match lead {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        _ => 4,
+    };
+    let bounds = match (lead, rest.is_empty()) {
+        (0xe0, true) => (0xa0, 0xbf),
+        (0xed, true) => (0x80, 0x9f),
+        (0xf0, true) => (0x90, 0xbf),
+        (0xf4, true) => (0x80, 0x8f),
+        _ => (0x80, 0xbf),
+    };
+    Some((width - pending.len(), bounds))
+}
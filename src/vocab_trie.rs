@@ -0,0 +1,168 @@
+use crate::{PrefixIndex, SortedTokenId, SortedTokenRange, TokenId};
+
+pub type VocabTrieNodeId = usize;
+
+pub const VOCAB_TRIE_ROOT_NODE_ID: VocabTrieNodeId = 0;
+
+/// The prefix tree of a vocab, with nodes numbered in DFS order,
+/// visiting children in ascending order of their bytes.
+///
+/// Tokens under a node form a range of sorted tokens,
+/// led by the tokens ending right at the node.
+#[derive(Clone, Debug)]
+pub struct VocabTrie {
+    order: Vec<TokenId>,
+    parents: Vec<VocabTrieNodeId>,
+    bytes: Vec<u8>,
+    depths: Vec<usize>,
+    rank_ranges: Vec<SortedTokenRange>,
+    exact_uppers: Vec<SortedTokenId>,
+    child_offsets: Vec<usize>,
+    children: Vec<VocabTrieNodeId>,
+}
+
+impl VocabTrie {
+    pub fn new<P: PrefixIndex>(index: &P) -> Self {
+        let vocab = index.vocab();
+        let order = index.order().to_vec();
+        let vocab_size = order.len() as SortedTokenId;
+
+        let mut res = Self {
+            order: Vec::new(),
+            parents: vec![VOCAB_TRIE_ROOT_NODE_ID],
+            bytes: vec![0],
+            depths: vec![0],
+            rank_ranges: vec![SortedTokenRange::default()],
+            exact_uppers: vec![0],
+            child_offsets: Vec::new(),
+            children: Vec::new(),
+        };
+
+        // path[d] is the node of the first `d` bytes of the previous token
+        let mut path = vec![VOCAB_TRIE_ROOT_NODE_ID];
+        let mut prev_token: &[u8] = &[];
+        for (k, &id) in order.iter().enumerate() {
+            let k = k as SortedTokenId;
+            let token = vocab.get(id as usize).unwrap_or_default();
+            let common_len = token
+                .iter()
+                .zip(prev_token)
+                .take_while(|(a, b)| a == b)
+                .count();
+            for node in path.drain(common_len + 1..) {
+                res.rank_ranges[node].upper = k;
+            }
+            for (depth, &byte) in token.iter().enumerate().skip(common_len) {
+                let node = res.parents.len();
+                res.parents.push(path[depth]);
+                res.bytes.push(byte);
+                res.depths.push(depth + 1);
+                res.rank_ranges
+                    .push(SortedTokenRange { lower: k, upper: k });
+                res.exact_uppers.push(k);
+                path.push(node);
+            }
+            res.exact_uppers[path[token.len()]] = k + 1;
+            prev_token = token;
+        }
+        for node in path {
+            res.rank_ranges[node].upper = vocab_size;
+        }
+
+        let num_of_nodes = res.parents.len();
+        res.child_offsets = vec![0; num_of_nodes + 1];
+        res.parents
+            .iter()
+            .skip(1)
+            .for_each(|&p| res.child_offsets[p + 1] += 1);
+        for i in 0..num_of_nodes {
+            res.child_offsets[i + 1] += res.child_offsets[i];
+        }
+        let mut cursor = res.child_offsets.clone();
+        res.children = vec![VOCAB_TRIE_ROOT_NODE_ID; num_of_nodes - 1];
+        for (node, &p) in res.parents.iter().enumerate().skip(1) {
+            res.children[cursor[p]] = node;
+            cursor[p] += 1;
+        }
+
+        res.order = order;
+        res
+    }
+
+    pub fn num_of_nodes(&self) -> usize {
+        self.parents.len()
+    }
+
+    pub fn parent(&self, node: VocabTrieNodeId) -> Option<VocabTrieNodeId> {
+        (node != VOCAB_TRIE_ROOT_NODE_ID)
+            .then(|| self.parents.get(node).copied())
+            .flatten()
+    }
+
+    /// Children of a node in ascending order of their bytes.
+    pub fn children(&self, node: VocabTrieNodeId) -> &[VocabTrieNodeId] {
+        match self.child_offsets.get(node..node + 2) {
+            Some(&[lower, upper]) => &self.children[lower..upper],
+            _ => &[],
+        }
+    }
+
+    pub fn child(&self, node: VocabTrieNodeId, byte: u8) -> Option<VocabTrieNodeId> {
+        let children = self.children(node);
+        let i = children
+            .binary_search_by_key(&byte, |&c| self.bytes[c])
+            .ok()?;
+        Some(children[i])
+    }
+
+    /// The last byte of the string of a node, `None` for the root.
+    pub fn byte(&self, node: VocabTrieNodeId) -> Option<u8> {
+        self.parent(node).map(|_| self.bytes[node])
+    }
+
+    /// Length of the string of a node in bytes.
+    pub fn depth(&self, node: VocabTrieNodeId) -> Option<usize> {
+        self.depths.get(node).copied()
+    }
+
+    /// The string of a node.
+    pub fn bytes(&self, node: VocabTrieNodeId) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.depth(node).unwrap_or(0));
+        let mut node = node;
+        while let Some(parent) = self.parent(node) {
+            res.push(self.bytes[node]);
+            node = parent;
+        }
+        res.reverse();
+        res
+    }
+
+    /// Walks down from the root along `bytes`.
+    pub fn find<B: AsRef<[u8]>>(&self, bytes: B) -> Option<VocabTrieNodeId> {
+        bytes
+            .as_ref()
+            .iter()
+            .try_fold(VOCAB_TRIE_ROOT_NODE_ID, |node, &byte| {
+                self.child(node, byte)
+            })
+    }
+
+    /// The range of sorted tokens prefixed with the string of a node.
+    pub fn rank_range(&self, node: VocabTrieNodeId) -> Option<SortedTokenRange> {
+        self.rank_ranges.get(node).cloned()
+    }
+
+    /// The range of sorted tokens equal to the string of a node.
+    pub fn exact_rank_range(&self, node: VocabTrieNodeId) -> Option<SortedTokenRange> {
+        let lower = self.rank_ranges.get(node)?.lower;
+        let upper = self.exact_uppers[node];
+        (lower < upper).then_some(SortedTokenRange { lower, upper })
+    }
+
+    /// Original ids of tokens equal to the string of a node.
+    pub fn token_ids(&self, node: VocabTrieNodeId) -> &[TokenId] {
+        self.exact_rank_range(node)
+            .map(|r| &self.order[r.lower as usize..r.upper as usize])
+            .unwrap_or_default()
+    }
+}